@@ -9,6 +9,7 @@ pub mod discord;
 pub mod keybind;
 pub mod line;
 pub mod logger;
+pub mod netcode;
 pub mod persist;
 pub mod shader_scene;
 pub mod sub_event_handler;
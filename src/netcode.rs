@@ -0,0 +1,10 @@
+mod interpolation;
+mod lockstep;
+mod prediction;
+
+pub use interpolation::{Angle, Interpolate, InterpolationBuffer, InterpolationConfig};
+pub use lockstep::{
+    Desync, LockstepClient, LockstepClientMessage, LockstepConfig, LockstepServer,
+    LockstepServerMessage, checksum,
+};
+pub use prediction::{AuthoritativeState, Predictor, SequencedInput};
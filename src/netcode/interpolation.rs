@@ -0,0 +1,212 @@
+use std::{
+    collections::VecDeque,
+    f32::consts::{PI, TAU},
+    time::{Duration, Instant},
+};
+
+use ggez::{glam::Vec2, graphics::Color};
+use serde::{Deserialize, Serialize};
+
+pub trait Interpolate: Clone {
+    fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+impl Interpolate for f32 {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        lerp(*self, *other, t)
+    }
+}
+
+impl Interpolate for Vec2 {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        self.lerp(*other, t)
+    }
+}
+
+impl Interpolate for Color {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        Color::new(
+            lerp(self.r, other.r, t).clamp(0.0, 1.0),
+            lerp(self.g, other.g, t).clamp(0.0, 1.0),
+            lerp(self.b, other.b, t).clamp(0.0, 1.0),
+            lerp(self.a, other.a, t).clamp(0.0, 1.0),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Angle(pub f32);
+
+impl Interpolate for Angle {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        let delta = (other.0 - self.0 + PI).rem_euclid(TAU) - PI;
+        Angle(self.0 + delta * t)
+    }
+}
+
+impl<A, B> Interpolate for (A, B)
+where
+    A: Interpolate,
+    B: Interpolate,
+{
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        (
+            self.0.interpolate(&other.0, t),
+            self.1.interpolate(&other.1, t),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterpolationConfig {
+    pub delay: Duration,
+    pub max_extrapolation: Duration,
+    pub capacity: usize,
+}
+
+impl Default for InterpolationConfig {
+    fn default() -> Self {
+        InterpolationConfig {
+            delay: Duration::from_millis(100),
+            max_extrapolation: Duration::from_millis(250),
+            capacity: 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InterpolationBuffer<T> {
+    config: InterpolationConfig,
+    snapshots: VecDeque<(Instant, T)>,
+}
+
+impl<T> Default for InterpolationBuffer<T> {
+    fn default() -> Self {
+        Self::new(InterpolationConfig::default())
+    }
+}
+
+impl<T> InterpolationBuffer<T> {
+    pub fn new(config: InterpolationConfig) -> Self {
+        InterpolationBuffer {
+            config,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    pub fn latest(&self) -> Option<&T> {
+        self.snapshots.back().map(|(_, state)| state)
+    }
+
+    pub fn push(&mut self, state: T) {
+        self.push_at(Instant::now(), state);
+    }
+
+    pub fn push_at(&mut self, time: Instant, state: T) {
+        let index = self.snapshots.partition_point(|(other, _)| *other <= time);
+        self.snapshots.insert(index, (time, state));
+        if let Some(render_time) = time.checked_sub(self.config.delay) {
+            while self.snapshots.len() > 2 && self.snapshots[1].0 <= render_time {
+                self.snapshots.pop_front();
+            }
+        }
+        while self.snapshots.len() > self.config.capacity.max(2) {
+            self.snapshots.pop_front();
+        }
+    }
+}
+
+impl<T> InterpolationBuffer<T>
+where
+    T: Interpolate,
+{
+    pub fn sample(&self) -> Option<T> {
+        self.sample_at(Instant::now())
+    }
+
+    pub fn sample_at(&self, now: Instant) -> Option<T> {
+        let render_time = now.checked_sub(self.config.delay).unwrap_or(now);
+        let index = self
+            .snapshots
+            .partition_point(|(time, _)| *time <= render_time);
+        let (from, to) = match index {
+            0 => return self.snapshots.front().map(|(_, state)| state.clone()),
+            index if index < self.snapshots.len() => {
+                (&self.snapshots[index - 1], &self.snapshots[index])
+            }
+            _ if self.snapshots.len() < 2 => {
+                return self.snapshots.back().map(|(_, state)| state.clone());
+            }
+            index => (&self.snapshots[index - 2], &self.snapshots[index - 1]),
+        };
+        let render_time = render_time.min(to.0 + self.config.max_extrapolation);
+        let span = (to.0 - from.0).as_secs_f32();
+        if span <= 0.0 {
+            return Some(to.1.clone());
+        }
+        let t = (render_time - from.0).as_secs_f32() / span;
+        Some(from.1.interpolate(&to.1, t))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        f32::consts::PI,
+        time::{Duration, Instant},
+    };
+
+    use super::{Angle, Interpolate, InterpolationBuffer, InterpolationConfig};
+
+    fn assert_close(sample: Option<f32>, expected: f32) {
+        let sample = sample.unwrap();
+        assert!((sample - expected).abs() < 1e-3, "{sample} != {expected}");
+    }
+
+    #[test]
+    fn test_angle() {
+        let halfway = Angle(PI - 0.1).interpolate(&Angle(-PI + 0.1), 0.5);
+        assert!((halfway.0 - PI).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_interpolation_buffer() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut buffer = InterpolationBuffer::new(InterpolationConfig {
+            delay: ms(100),
+            max_extrapolation: ms(50),
+            capacity: 8,
+        });
+        assert_eq!(buffer.sample_at(start), None);
+
+        buffer.push_at(start, 0.0);
+        buffer.push_at(start + ms(100), 10.0);
+        assert_close(buffer.sample_at(start + ms(50)), 0.0);
+        assert_close(buffer.sample_at(start + ms(150)), 5.0);
+        assert_close(buffer.sample_at(start + ms(200)), 10.0);
+        assert_close(buffer.sample_at(start + ms(230)), 13.0);
+        assert_close(buffer.sample_at(start + ms(500)), 15.0);
+
+        buffer.push_at(start + ms(300), 30.0);
+        buffer.push_at(start + ms(200), 20.0);
+        assert_close(buffer.sample_at(start + ms(350)), 25.0);
+        assert_eq!(buffer.len(), 3);
+    }
+}
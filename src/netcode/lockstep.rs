@@ -0,0 +1,445 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    io,
+};
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::transport::{
+    Bincode, ClientServerMessage, ClientTransport, CodecExt, ConnectionId, MessageHost,
+};
+
+const MAX_INPUT_LEAD: u64 = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LockstepConfig {
+    pub input_delay: u64,
+    pub checksum_interval: u64,
+}
+
+impl Default for LockstepConfig {
+    fn default() -> Self {
+        LockstepConfig {
+            input_delay: 2,
+            checksum_interval: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LockstepClientMessage<I> {
+    Input { tick: u64, input: I },
+    Checksum { tick: u64, checksum: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LockstepServerMessage<I> {
+    Tick {
+        tick: u64,
+        inputs: Vec<(ConnectionId, I)>,
+    },
+    Desync(Desync),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Desync {
+    pub tick: u64,
+    pub checksums: BTreeMap<ConnectionId, u64>,
+}
+
+/// Hashes the bincode encoding of `state`, so every client must serialize it identically:
+/// use ordered collections such as `BTreeMap` rather than `HashMap` or `HashSet`.
+pub fn checksum(state: &impl Serialize) -> Result<u64, io::Error> {
+    Ok(
+        (Bincode.encode(state)?.iter()).fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        }),
+    )
+}
+
+#[derive(Debug)]
+pub struct LockstepServer<I> {
+    config: LockstepConfig,
+    players: BTreeSet<ConnectionId>,
+    next_tick: u64,
+    checked_tick: u64,
+    inputs: BTreeMap<u64, BTreeMap<ConnectionId, I>>,
+    checksums: BTreeMap<u64, BTreeMap<ConnectionId, u64>>,
+}
+
+impl<I> LockstepServer<I>
+where
+    I: Clone,
+{
+    pub fn new(players: impl IntoIterator<Item = ConnectionId>, config: LockstepConfig) -> Self {
+        LockstepServer {
+            config,
+            players: players.into_iter().collect(),
+            next_tick: 0,
+            checked_tick: 0,
+            inputs: BTreeMap::new(),
+            checksums: BTreeMap::new(),
+        }
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = ConnectionId> + '_ {
+        self.players.iter().copied()
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.next_tick
+    }
+
+    fn receive(
+        &mut self,
+        id: ConnectionId,
+        message: LockstepClientMessage<I>,
+    ) -> Vec<LockstepServerMessage<I>> {
+        if !self.players.contains(&id) {
+            debug!("ignoring lockstep message from non-player {id}");
+            return Vec::new();
+        }
+        let max_input_tick = self.next_tick + self.config.input_delay + MAX_INPUT_LEAD;
+        match message {
+            LockstepClientMessage::Input { tick, .. } if tick < self.next_tick => {
+                debug!("ignoring late input for tick {tick} from {id}");
+            }
+            LockstepClientMessage::Input { tick, .. } if tick > max_input_tick => {
+                debug!("ignoring input for tick {tick} from {id}, too far ahead");
+            }
+            LockstepClientMessage::Input { tick, input } => {
+                self.inputs
+                    .entry(tick)
+                    .or_default()
+                    .entry(id)
+                    .or_insert(input);
+            }
+            LockstepClientMessage::Checksum { tick, .. }
+                if tick < self.checked_tick || tick >= self.next_tick =>
+            {
+                debug!("ignoring checksum for tick {tick} from {id}");
+            }
+            LockstepClientMessage::Checksum { tick, checksum } => {
+                self.checksums.entry(tick).or_default().insert(id, checksum);
+            }
+        }
+        self.resolve()
+    }
+
+    fn remove(&mut self, id: ConnectionId) -> Vec<LockstepServerMessage<I>> {
+        self.players.remove(&id);
+        self.resolve()
+    }
+
+    fn resolve(&mut self) -> Vec<LockstepServerMessage<I>> {
+        let mut messages = Vec::new();
+        while !self.players.is_empty()
+            && let Some(inputs) = self.inputs.get(&self.next_tick)
+            && self.players.iter().all(|id| inputs.contains_key(id))
+        {
+            let inputs = self.inputs.remove(&self.next_tick).unwrap();
+            messages.push(LockstepServerMessage::Tick {
+                tick: self.next_tick,
+                inputs: (inputs.into_iter())
+                    .filter(|(id, _)| self.players.contains(id))
+                    .collect(),
+            });
+            self.next_tick += 1;
+        }
+        let complete: Vec<_> = (self.checksums.iter())
+            .filter(|(_, checksums)| self.players.iter().all(|id| checksums.contains_key(id)))
+            .map(|(&tick, _)| tick)
+            .collect();
+        if let Some(&last) = complete.last() {
+            self.checked_tick = last + 1;
+        }
+        for tick in complete {
+            let mut checksums = self.checksums.remove(&tick).unwrap();
+            checksums.retain(|id, _| self.players.contains(id));
+            let mut values = checksums.values();
+            let first = values.next();
+            if values.any(|checksum| Some(checksum) != first) {
+                error!("Lockstep desync detected at tick {tick}");
+                messages.push(LockstepServerMessage::Desync(Desync { tick, checksums }));
+            }
+        }
+        // players report checksums in order, so anything older than a complete tick never will be
+        self.checksums = self.checksums.split_off(&self.checked_tick);
+        messages
+    }
+
+    fn broadcast<M>(
+        &self,
+        server: &impl MessageHost,
+        messages: &[LockstepServerMessage<I>],
+    ) -> Option<Desync>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+        M::ServerMessage: From<LockstepServerMessage<I>>,
+    {
+        let mut desync = None;
+        for message in messages {
+            for id in &self.players {
+                (server.send_to::<M>(*id, message.clone().into()))
+                    .map_err(|err| debug!("unable to send lockstep message to {id}: {err}"))
+                    .ok();
+            }
+            if let LockstepServerMessage::Desync(found) = message {
+                desync.get_or_insert_with(|| found.clone());
+            }
+        }
+        desync
+    }
+
+    pub fn handle<M>(
+        &mut self,
+        server: &impl MessageHost,
+        id: ConnectionId,
+        message: LockstepClientMessage<I>,
+    ) -> Option<Desync>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+        M::ServerMessage: From<LockstepServerMessage<I>>,
+    {
+        let messages = self.receive(id, message);
+        self.broadcast::<M>(server, &messages)
+    }
+
+    pub fn remove_player<M>(
+        &mut self,
+        server: &impl MessageHost,
+        id: ConnectionId,
+    ) -> Option<Desync>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+        M::ServerMessage: From<LockstepServerMessage<I>>,
+    {
+        let messages = self.remove(id);
+        self.broadcast::<M>(server, &messages)
+    }
+}
+
+#[derive(Debug)]
+pub struct LockstepClient<I> {
+    config: LockstepConfig,
+    tick: u64,
+    next_input_tick: u64,
+    held_inputs: VecDeque<I>,
+    confirmed: BTreeMap<u64, Vec<(ConnectionId, I)>>,
+    desync: Option<Desync>,
+}
+
+impl<I> LockstepClient<I>
+where
+    I: Clone + Default,
+{
+    pub fn new(config: LockstepConfig) -> Self {
+        LockstepClient {
+            config,
+            tick: 0,
+            next_input_tick: 0,
+            held_inputs: VecDeque::new(),
+            confirmed: BTreeMap::new(),
+            desync: None,
+        }
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn desync(&self) -> Option<&Desync> {
+        self.desync.as_ref()
+    }
+
+    fn inputs(&mut self, input: I) -> Vec<LockstepClientMessage<I>> {
+        self.held_inputs.push_back(input);
+        self.pending_inputs()
+    }
+
+    fn pending_inputs(&mut self) -> Vec<LockstepClientMessage<I>> {
+        if self.held_inputs.is_empty() {
+            return Vec::new();
+        }
+        let target = self.tick + self.config.input_delay;
+        let free = (target + 1).saturating_sub(self.next_input_tick) as usize;
+        if free == 0 {
+            debug!("holding input until tick {} is confirmed", self.tick);
+            return Vec::new();
+        }
+        // held inputs take one tick each, the newest landing as close to the target as possible
+        let idle = free.saturating_sub(self.held_inputs.len());
+        let mut messages = Vec::new();
+        for slot in 0..free {
+            let input = if slot < idle {
+                I::default()
+            } else {
+                self.held_inputs.pop_front().unwrap()
+            };
+            messages.push(LockstepClientMessage::Input {
+                tick: self.next_input_tick,
+                input,
+            });
+            self.next_input_tick += 1;
+        }
+        messages
+    }
+
+    pub fn submit<M>(&mut self, transport: &impl ClientTransport, input: I) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+        M::ClientMessage: From<LockstepClientMessage<I>>,
+    {
+        for message in self.inputs(input) {
+            transport.send::<M>(message.into())?;
+        }
+        Ok(())
+    }
+
+    pub fn handle(&mut self, message: LockstepServerMessage<I>) {
+        match message {
+            LockstepServerMessage::Tick { tick, inputs } if tick >= self.tick => {
+                self.confirmed.insert(tick, inputs);
+            }
+            LockstepServerMessage::Tick { tick, .. } => {
+                debug!("ignoring duplicate inputs for tick {tick}");
+            }
+            LockstepServerMessage::Desync(desync) => {
+                error!("Lockstep desync at tick {}", desync.tick);
+                self.desync.get_or_insert(desync);
+            }
+        }
+    }
+
+    pub fn advance(&mut self) -> Option<(u64, Vec<(ConnectionId, I)>)> {
+        let inputs = self.confirmed.remove(&self.tick)?;
+        self.tick += 1;
+        Some((self.tick - 1, inputs))
+    }
+
+    pub fn update<M>(
+        &mut self,
+        transport: &impl ClientTransport,
+        mut step: impl FnMut(u64, &[(ConnectionId, I)]) -> u64,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+        M::ClientMessage: From<LockstepClientMessage<I>>,
+    {
+        while let Some((tick, inputs)) = self.advance() {
+            let checksum = step(tick, &inputs);
+            if self.config.checksum_interval > 0 && tick % self.config.checksum_interval == 0 {
+                transport.send::<M>(LockstepClientMessage::Checksum { tick, checksum }.into())?;
+            }
+        }
+        for message in self.pending_inputs() {
+            transport.send::<M>(message.into())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::transport::ConnectionId;
+
+    use super::{
+        LockstepClient, LockstepClientMessage, LockstepConfig, LockstepServer,
+        LockstepServerMessage,
+    };
+
+    #[test]
+    fn test_lockstep() {
+        let (a, b) = (ConnectionId(1), ConnectionId(2));
+        let config = LockstepConfig {
+            input_delay: 2,
+            checksum_interval: 1,
+        };
+        let mut server = LockstepServer::<i32>::new([a, b], config);
+        let mut client = LockstepClient::<i32>::new(config);
+
+        let inputs = client.inputs(5);
+        assert_eq!(inputs.len(), 3);
+        assert_eq!(client.inputs(6), vec![]);
+        assert_eq!(client.inputs(8), vec![]);
+        for input in inputs {
+            assert!(server.receive(a, input).is_empty());
+        }
+        let flood = LockstepClientMessage::Input {
+            tick: u64::MAX - 1,
+            input: 0,
+        };
+        assert!(server.receive(b, flood).is_empty());
+        assert!(
+            server
+                .receive(
+                    b,
+                    LockstepClientMessage::Checksum {
+                        tick: 5,
+                        checksum: 0
+                    }
+                )
+                .is_empty()
+        );
+        assert!(server.inputs.keys().all(|&tick| tick <= 2));
+        assert!(server.checksums.is_empty());
+        let ticks = server.receive(b, LockstepClientMessage::Input { tick: 0, input: 7 });
+        assert_eq!(
+            ticks,
+            vec![LockstepServerMessage::Tick {
+                tick: 0,
+                inputs: vec![(a, 0), (b, 7)],
+            }]
+        );
+        assert_eq!(server.tick(), 1);
+
+        for tick in ticks {
+            client.handle(tick);
+        }
+        assert_eq!(client.advance(), Some((0, vec![(a, 0), (b, 7)])));
+        assert_eq!(client.advance(), None);
+        assert_eq!(
+            client.pending_inputs(),
+            vec![LockstepClientMessage::Input { tick: 3, input: 6 }]
+        );
+        assert_eq!(client.pending_inputs(), vec![]);
+        client.handle(LockstepServerMessage::Tick {
+            tick: 1,
+            inputs: vec![],
+        });
+        assert_eq!(client.advance(), Some((1, vec![])));
+        assert_eq!(
+            client.pending_inputs(),
+            vec![LockstepClientMessage::Input { tick: 4, input: 8 }]
+        );
+        assert_eq!(client.pending_inputs(), vec![]);
+
+        assert!(
+            server
+                .receive(
+                    a,
+                    LockstepClientMessage::Checksum {
+                        tick: 0,
+                        checksum: 1
+                    }
+                )
+                .is_empty()
+        );
+        let desync = server.receive(
+            b,
+            LockstepClientMessage::Checksum {
+                tick: 0,
+                checksum: 2,
+            },
+        );
+        let [LockstepServerMessage::Desync(desync)] = desync.as_slice() else {
+            panic!("expected a desync, got {desync:?}");
+        };
+        assert_eq!(desync.tick, 0);
+
+        let ticks = server.remove(b);
+        assert_eq!(ticks.len(), 2);
+        assert_eq!(server.tick(), 3);
+    }
+}
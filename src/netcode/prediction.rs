@@ -0,0 +1,128 @@
+use std::{collections::VecDeque, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::transport::{ClientServerMessage, ClientTransport};
+
+const MAX_PENDING_INPUTS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SequencedInput<I> {
+    pub sequence: u64,
+    pub input: I,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthoritativeState<S> {
+    pub state: S,
+    pub last_input: Option<u64>,
+}
+
+type Step<S, I> = dyn FnMut(&mut S, &I);
+
+pub struct Predictor<S, I> {
+    state: S,
+    step: Box<Step<S, I>>,
+    next_sequence: u64,
+    acknowledged: Option<u64>,
+    pending: VecDeque<SequencedInput<I>>,
+}
+
+impl<S, I> Predictor<S, I>
+where
+    I: Clone,
+{
+    pub fn new(state: S, step: impl FnMut(&mut S, &I) + 'static) -> Self {
+        Predictor {
+            state,
+            step: Box::new(step),
+            next_sequence: 0,
+            acknowledged: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn acknowledged(&self) -> Option<u64> {
+        self.acknowledged
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn predict(&mut self, input: I) -> SequencedInput<I> {
+        let input = SequencedInput {
+            sequence: self.next_sequence,
+            input,
+        };
+        self.next_sequence += 1;
+        (self.step)(&mut self.state, &input.input);
+        self.pending.push_back(input.clone());
+        if self.pending.len() > MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        input
+    }
+
+    pub fn update<M>(&mut self, transport: &impl ClientTransport, input: I) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+        M::ClientMessage: From<SequencedInput<I>>,
+    {
+        let input = self.predict(input);
+        transport.send::<M>(input.into())
+    }
+
+    pub fn reconcile(&mut self, authoritative: AuthoritativeState<S>) {
+        let AuthoritativeState { state, last_input } = authoritative;
+        if last_input < self.acknowledged {
+            return;
+        }
+        self.acknowledged = last_input;
+        if let Some(last_input) = last_input {
+            self.pending.retain(|input| input.sequence > last_input);
+        }
+        self.state = state;
+        for input in &self.pending {
+            (self.step)(&mut self.state, &input.input);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuthoritativeState, Predictor};
+
+    #[test]
+    fn test_reconcile() {
+        let mut predictor = Predictor::new(0i32, |position, velocity: &i32| *position += velocity);
+        for _ in 0..5 {
+            predictor.predict(1);
+        }
+        assert_eq!(*predictor.state(), 5);
+
+        predictor.reconcile(AuthoritativeState {
+            state: 10,
+            last_input: Some(1),
+        });
+        assert_eq!(*predictor.state(), 13);
+        assert_eq!(predictor.pending(), 3);
+
+        predictor.reconcile(AuthoritativeState {
+            state: 0,
+            last_input: Some(0),
+        });
+        assert_eq!(*predictor.state(), 13);
+
+        predictor.reconcile(AuthoritativeState {
+            state: 20,
+            last_input: Some(4),
+        });
+        assert_eq!(*predictor.state(), 20);
+        assert_eq!(predictor.pending(), 0);
+    }
+}
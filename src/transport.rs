@@ -1,22 +1,88 @@
 use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
     io::{self, ErrorKind, Read, Write},
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+    },
     ops::{Deref, DerefMut},
-    sync::mpsc::{Receiver, Sender, channel},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, Sender, channel},
+    },
     thread::{self, JoinHandle},
     time::Duration,
 };
 
 use log::{debug, error};
+use ring::rand::{SecureRandom, SystemRandom};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
-use crate::util::ResultExt;
+use compression::Compressor;
+use handshake::{Hello, SessionTicket, Verdict, Welcome};
+use heartbeat::{Heartbeat, HeartbeatThread};
+use recording::{Recorder, Recording};
+use replication::{Replication, Snapshot};
+use rpc::PendingRequests;
+use simulator::{LinkKind, SimulatedLink};
+use stats::TransferStats;
+use tls::TlsSession;
+use udp::{UdpChannel, UdpServer};
+
+use crate::{
+    persist::PersistenceManager,
+    util::{HashMapBag, ResultExt},
+};
+
+mod admission;
+pub mod async_transport;
+mod codec;
+mod compression;
+mod disconnect;
+pub mod discovery;
+mod endpoint;
+mod handshake;
+mod heartbeat;
+pub mod loopback;
+mod reconnect;
+mod recording;
+mod replication;
+mod rpc;
+mod simulator;
+mod stats;
+mod tls;
+mod udp;
+
+pub use admission::{Admission, AdmissionHook, BanList};
+pub use codec::{Bincode, Codec, CodecConfig, CodecExt, Json, MessagePack};
+pub use compression::{Compression, CompressionConfig};
+pub use disconnect::DisconnectReason;
+pub use endpoint::{ClientTransport, MessageHost, ServerTransport};
+pub use handshake::RejectReason;
+pub use heartbeat::HeartbeatConfig;
+pub use reconnect::ReconnectPolicy;
+pub use recording::{
+    Replay, ReplayClientNetworkEvent, ReplayServerNetworkEvent, ReplaySpeed, ReplayTransport,
+};
+pub use rpc::{RequestId, RpcHandle};
+pub use simulator::NetworkConditions;
+pub use stats::{ConnectionStats, FrameKind, FrameStats, StatsRecorder};
+pub use tls::{TlsClient, TlsServer};
 
-const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub trait ClientServerMessage {
     type ClientMessage;
     type ServerMessage;
+
+    const PROTOCOL_ID: &'static str = "";
+    const PROTOCOL_VERSION: u32 = 0;
+    const MIN_PROTOCOL_VERSION: u32 = Self::PROTOCOL_VERSION;
 }
 
 #[derive(Debug)]
@@ -26,60 +92,466 @@ pub enum NetworkEvent<T, M> {
         my_socket_addr: SocketAddr,
     },
     Message(M),
-    Disconnect,
+    Request {
+        id: RequestId,
+        message: M,
+    },
+    Disconnect(DisconnectReason),
+    Rejected(RejectReason),
+    State(M),
+    Suspended,
+    Resumed {
+        transport: T,
+    },
+}
+
+#[derive(Debug)]
+pub enum ServerEvent<T, M> {
+    Connection(Peer, NetworkEvent<T, M>),
+    ListenerError(io::Error),
+}
+
+#[derive(Debug)]
+pub enum ClientEvent<T, M> {
+    Connection(NetworkEvent<T, M>),
+    Reconnecting { attempt: u32, delay: Duration },
+    GaveUp,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize,
+)]
+pub struct ConnectionId(pub u64);
+
+impl Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Peer {
+    pub id: ConnectionId,
+    pub addr: SocketAddr,
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.id, self.addr)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Reliable,
+    Unreliable,
+    Sequenced,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    pub udp: bool,
+    pub tls: Option<TlsServer>,
+    pub heartbeat: HeartbeatConfig,
+    pub session_grace: Option<Duration>,
+    pub codec: CodecConfig,
+    pub compression: CompressionConfig,
+    pub conditions: Option<NetworkConditions>,
+    pub admission: Option<AdmissionHook>,
+    pub max_connections: Option<usize>,
+    pub bans: BanList,
+    pub recording: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    pub udp: bool,
+    pub tls: Option<TlsClient>,
+    pub heartbeat: HeartbeatConfig,
+    pub reconnect: ReconnectPolicy,
+    pub codec: CodecConfig,
+    pub compression: CompressionConfig,
+    pub conditions: Option<NetworkConditions>,
+    pub recording: Option<PathBuf>,
 }
 
 pub type ServerNetworkEvent<M> =
-    NetworkEvent<ServersideTransport, <M as ClientServerMessage>::ClientMessage>;
+    ServerEvent<ServersideTransport, <M as ClientServerMessage>::ClientMessage>;
 pub type ClientNetworkEvent<M> =
-    NetworkEvent<ClientsideTransport, <M as ClientServerMessage>::ServerMessage>;
+    ClientEvent<ClientsideTransport, <M as ClientServerMessage>::ServerMessage>;
+
+#[derive(Serialize, Deserialize)]
+enum Frame<M> {
+    Message(M),
+    Ping(u64),
+    Pong(u64),
+    Request(RequestId, M),
+    Response(RequestId, M),
+    Goodbye(DisconnectReason),
+    Snapshot(Snapshot),
+    SnapshotAck(u64),
+}
+
+impl<M> Frame<M> {
+    fn kind(&self) -> FrameKind {
+        match self {
+            Frame::Message(_) => FrameKind::Message,
+            Frame::Ping(_) => FrameKind::Ping,
+            Frame::Pong(_) => FrameKind::Pong,
+            Frame::Request(..) => FrameKind::Request,
+            Frame::Response(..) => FrameKind::Response,
+            Frame::Goodbye(_) => FrameKind::Goodbye,
+            Frame::Snapshot(_) => FrameKind::Snapshot,
+            Frame::SnapshotAck(_) => FrameKind::SnapshotAck,
+        }
+    }
+}
+
+enum Incoming<M> {
+    Message(M),
+    Request(RequestId, M),
+    Response(RequestId, M),
+    State(M),
+    Goodbye(DisconnectReason),
+}
+
+fn client_message<M>(message: M) -> Result<M::ClientMessage, io::Error>
+where
+    M: ClientServerMessage,
+    M::ClientMessage: TryFrom<M>,
+{
+    message.try_into().map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            "Received a serverside message from a client",
+        )
+    })
+}
+
+fn server_message<M>(message: M) -> Result<M::ServerMessage, io::Error>
+where
+    M: ClientServerMessage,
+    M::ServerMessage: TryFrom<M>,
+{
+    message.try_into().map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            "Received a clientside message from the server",
+        )
+    })
+}
+
+fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        error!("Unable to read from the system random number generator");
+    }
+    u64::from_le_bytes(bytes)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Token([u8; 16]);
+
+impl Token {
+    fn generate() -> Result<Self, io::Error> {
+        let mut token = [0; 16];
+        (SystemRandom::new().fill(&mut token))
+            .map_err(|_| io::Error::other("Unable to generate a random token"))?;
+        Ok(Token(token))
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn not_connected(id: ConnectionId) -> io::Error {
+    io::Error::new(ErrorKind::NotFound, format!("No connection with id {id}"))
+}
 
 #[derive(Debug)]
-pub struct MessageTransporter(TcpStream);
+pub struct MessageTransporter {
+    stream: TcpStream,
+    tls: Option<TlsSession>,
+    udp: Option<UdpChannel>,
+    write_lock: Arc<Mutex<()>>,
+    heartbeat: Arc<Heartbeat>,
+    codec: CodecConfig,
+    compressor: Option<Compressor>,
+    requests: Arc<PendingRequests>,
+    simulator: Option<Arc<SimulatedLink>>,
+    stats: Arc<TransferStats>,
+    replication: Arc<Mutex<Replication>>,
+    recorder: Option<Recorder>,
+    protocol_version: u32,
+    connection_id: ConnectionId,
+}
 
 impl MessageTransporter {
     fn new(stream: TcpStream) -> Self {
-        Self(stream)
+        MessageTransporter {
+            stream,
+            tls: None,
+            udp: None,
+            write_lock: Default::default(),
+            heartbeat: Arc::new(Heartbeat::new()),
+            codec: CodecConfig::default(),
+            compressor: None,
+            requests: Default::default(),
+            simulator: None,
+            stats: Arc::new(TransferStats::new()),
+            replication: Default::default(),
+            recorder: None,
+            protocol_version: 0,
+            connection_id: ConnectionId::default(),
+        }
     }
 
     fn try_clone(&self) -> Result<Self, io::Error> {
-        Ok(MessageTransporter(self.0.try_clone()?))
+        Ok(MessageTransporter {
+            stream: self.stream.try_clone()?,
+            tls: self.tls.clone(),
+            udp: self.udp.clone(),
+            write_lock: self.write_lock.clone(),
+            heartbeat: self.heartbeat.clone(),
+            codec: self.codec.clone(),
+            compressor: self.compressor,
+            requests: self.requests.clone(),
+            simulator: self.simulator.clone(),
+            stats: self.stats.clone(),
+            replication: self.replication.clone(),
+            recorder: self.recorder.clone(),
+            protocol_version: self.protocol_version,
+            connection_id: self.connection_id,
+        })
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
     }
 
-    fn send<M>(&mut self, message: &M) -> Result<(), io::Error>
+    pub fn is_encrypted(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.heartbeat.rtt()
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        self.compressor.as_ref().map(Compressor::algorithm)
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.snapshot()
+    }
+
+    fn simulate(&mut self, conditions: NetworkConditions) -> Result<(), io::Error> {
+        let link = self.try_clone()?;
+        self.simulator = Some(SimulatedLink::spawn(
+            conditions,
+            LinkKind::Stream,
+            move |buf| link.write_all(buf),
+        ));
+        if let Some(udp) = &mut self.udp {
+            udp.simulate(conditions);
+        }
+        Ok(())
+    }
+
+    fn write_all(&self, buf: &[u8]) -> Result<(), io::Error> {
+        if let Some(simulator) = &self.simulator {
+            return simulator.submit(buf.to_vec());
+        }
+        self.write_direct(buf)
+    }
+
+    fn write_direct(&self, buf: &[u8]) -> Result<(), io::Error> {
+        let _guard = self.write_lock.lock().unwrap();
+        let result = match &self.tls {
+            Some(tls) => tls.write_all(&self.stream, buf),
+            None => (&self.stream).write_all(buf),
+        };
+        if let Err(err) = &result
+            && is_timeout(err)
+        {
+            debug!("write to connection {} timed out", self.connection_id);
+            let _ = self.stream.shutdown(Shutdown::Both);
+        }
+        result
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), io::Error> {
+        match &self.tls {
+            Some(tls) => tls.read_exact(&self.stream, buf),
+            None => self.stream.read_exact(buf),
+        }
+    }
+
+    fn send_encoded<M>(&self, kind: FrameKind, message: &M) -> Result<(), io::Error>
     where
         M: Serialize,
     {
-        let encoded_message: Vec<u8> =
-            bincode::serialize(message).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
-        let len = u64::to_le_bytes(encoded_message.len() as u64);
-        self.0.write_all(&len)?;
-        self.0.write_all(&encoded_message)?;
+        let frame = self.codec.encode_frame(message, self.compressor.as_ref())?;
+        self.write_all(&frame)?;
+        self.stats.sent(kind, frame.len());
         Ok(())
     }
 
+    fn send_frame<M>(&self, frame: &Frame<M>) -> Result<(), io::Error>
+    where
+        M: Serialize,
+    {
+        self.send_encoded(frame.kind(), frame)
+    }
+
+    fn send_handshake<M>(&self, message: &M) -> Result<(), io::Error>
+    where
+        M: Serialize,
+    {
+        self.send_encoded(FrameKind::Handshake, message)
+    }
+
+    fn send<M>(&self, message: &M) -> Result<(), io::Error>
+    where
+        M: Serialize,
+    {
+        self.send_frame(&Frame::Message(message))
+    }
+
+    fn send_snapshot(&self, state: &serde_json::Value) -> Result<(), io::Error> {
+        let snapshot = self.replication.lock().unwrap().snapshot(state.clone())?;
+        self.send_frame(&Frame::<()>::Snapshot(snapshot))
+    }
+
+    fn send_with<M>(&self, message: &M, delivery: Delivery) -> Result<(), io::Error>
+    where
+        M: Serialize,
+    {
+        match (&self.udp, delivery) {
+            (Some(udp), Delivery::Unreliable | Delivery::Sequenced) if udp.is_ready() => {
+                udp.send(message, delivery)
+            }
+            _ => self.send(message),
+        }
+    }
+
+    pub fn has_udp(&self) -> bool {
+        self.udp.as_ref().is_some_and(UdpChannel::is_ready)
+    }
+
+    // returns the payload along with the size of the frame on the wire
+    fn recv_payload(&mut self) -> Result<(Vec<u8>, usize), io::Error> {
+        let mut header_buf = [0u8; 8];
+        self.read_exact(&mut header_buf)?;
+        let (len, compressed) = self.codec.frame_header(header_buf)?;
+        let mut buf = vec![0; len];
+        self.read_exact(&mut buf)?;
+        if compressed {
+            buf = self.codec.decompress(&buf, self.compressor.as_ref())?;
+        }
+        Ok((buf, header_buf.len() + len))
+    }
+
+    fn recv_handshake<M>(&mut self) -> Result<M, io::Error>
+    where
+        M: DeserializeOwned,
+    {
+        let (payload, len) = self.recv_payload()?;
+        self.stats.received(FrameKind::Handshake, len);
+        self.codec.encoding.decode(&payload)
+    }
+
     fn recv<M>(&mut self) -> Result<M, io::Error>
     where
         M: DeserializeOwned,
     {
-        let mut len_buf = [0u8; 8];
-        self.0.read_exact(&mut len_buf)?;
-        let len = u64::from_le_bytes(len_buf) as usize;
-        if len > MAX_PACKET_SIZE {
-            return Err(io::Error::new(
-                ErrorKind::FileTooLarge,
-                format!("Message size cannot exceed {} bytes", MAX_PACKET_SIZE),
-            ));
+        loop {
+            match self.recv_incoming::<M>()? {
+                Incoming::Message(message) => return Ok(message),
+                Incoming::Request(id, _) | Incoming::Response(id, _) => {
+                    debug!("ignoring rpc frame for request {id}")
+                }
+                Incoming::State(_) => debug!("ignoring replicated state"),
+                Incoming::Goodbye(reason) => {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        format!("Peer disconnected: {reason}"),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn recv_incoming<M>(&mut self) -> Result<Incoming<M>, io::Error>
+    where
+        M: DeserializeOwned,
+    {
+        loop {
+            let (payload, len) = self.recv_payload().map_err(|err| {
+                if is_timeout(&err) {
+                    io::Error::new(ErrorKind::TimedOut, "Heartbeat timed out")
+                } else {
+                    err
+                }
+            })?;
+            let frame = self.codec.encoding.decode::<Frame<M>>(&payload)?;
+            self.stats.received(frame.kind(), len);
+            let incoming = match frame {
+                Frame::Message(message) => Incoming::Message(message),
+                Frame::Request(id, message) => Incoming::Request(id, message),
+                Frame::Response(id, message) => Incoming::Response(id, message),
+                Frame::Ping(timestamp) => {
+                    self.send_frame(&Frame::<()>::Pong(timestamp))?;
+                    continue;
+                }
+                Frame::Pong(timestamp) => {
+                    self.heartbeat.pong(timestamp);
+                    continue;
+                }
+                Frame::Snapshot(snapshot) => {
+                    let sequence = snapshot.sequence();
+                    let state = self.replication.lock().unwrap().receive(snapshot)?;
+                    self.send_frame(&Frame::<()>::SnapshotAck(sequence))?;
+                    Incoming::State(state)
+                }
+                Frame::SnapshotAck(sequence) => {
+                    self.replication.lock().unwrap().acknowledge(sequence);
+                    continue;
+                }
+                Frame::Goodbye(reason) => return Ok(Incoming::Goodbye(reason)),
+            };
+            if let Some(recorder) = &self.recorder {
+                recorder.frame(&payload);
+            }
+            return Ok(incoming);
         }
-        let mut buf = vec![0; len];
-        self.0.read_exact(&mut buf)?;
-        let message: M = bincode::deserialize(&buf[..])
-            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
-        Ok(message)
     }
 
-    pub fn shutdown(&mut self) -> Result<(), std::io::Error> {
-        self.0.shutdown(std::net::Shutdown::Both)
+    fn goodbye(&self, reason: &DisconnectReason) {
+        let goodbye = Frame::<()>::Goodbye(reason.clone());
+        let result: Result<(), io::Error> = try {
+            let frame = self
+                .codec
+                .encode_frame(&goodbye, self.compressor.as_ref())?;
+            self.write_direct(&frame)?;
+            self.stats.sent(FrameKind::Goodbye, frame.len());
+        };
+        result
+            .map_err(|err| debug!("unable to say goodbye: {err}"))
+            .ok();
+        let _ = self.shutdown();
+    }
+
+    pub fn shutdown(&self) -> Result<(), std::io::Error> {
+        if let Some(tls) = &self.tls {
+            tls.close(&self.stream).log_and_ignore();
+        }
+        self.stream.shutdown(Shutdown::Both)
     }
 }
 
@@ -87,7 +559,7 @@ impl Deref for MessageTransporter {
     type Target = TcpStream;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.stream
     }
 }
 
@@ -103,32 +575,51 @@ impl ClientsideTransport {
         Ok(Self(self.0.try_clone()?))
     }
 
-    pub fn send<M>(&mut self, message: M::ClientMessage) -> Result<(), io::Error>
+    pub fn send<M>(&self, message: M::ClientMessage) -> Result<(), io::Error>
     where
         M: ClientServerMessage + From<M::ClientMessage> + Serialize,
     {
         self.0.send(&M::from(message))
     }
 
-    pub fn blind_send<M>(&mut self, message: M::ClientMessage)
+    pub fn blind_send<M>(&self, message: M::ClientMessage)
     where
         M: ClientServerMessage + From<M::ClientMessage> + Serialize,
     {
         let _ = self.send::<M>(message);
     }
 
+    pub fn send_with<M>(
+        &self,
+        message: M::ClientMessage,
+        delivery: Delivery,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        self.0.send_with(&M::from(message), delivery)
+    }
+
+    pub fn request<M>(
+        &self,
+        message: M::ClientMessage,
+        timeout: Duration,
+    ) -> Result<RpcHandle<M>, io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize + 'static,
+        M::ServerMessage: TryFrom<M>,
+    {
+        let handle = RpcHandle::register(&self.0.requests, timeout);
+        (self.0).send_frame(&Frame::Request(handle.id(), &M::from(message)))?;
+        Ok(handle)
+    }
+
     pub fn recv<M>(&mut self) -> Result<M::ServerMessage, io::Error>
     where
         M: ClientServerMessage + DeserializeOwned,
         M::ServerMessage: TryFrom<M>,
     {
-        let Ok(message) = self.0.recv::<M>()?.try_into() else {
-            return Err(io::Error::new(
-                ErrorKind::InvalidData,
-                "Received a clientside message from the server",
-            ));
-        };
-        Ok(message)
+        server_message::<M>(self.0.recv::<M>()?)
     }
 }
 
@@ -158,32 +649,51 @@ impl ServersideTransport {
         Ok(Self(self.0.try_clone()?))
     }
 
-    pub fn send<M>(&mut self, message: M::ServerMessage) -> Result<(), io::Error>
+    pub fn send<M>(&self, message: M::ServerMessage) -> Result<(), io::Error>
     where
         M: ClientServerMessage + From<M::ServerMessage> + Serialize,
     {
         self.0.send(&M::from(message))
     }
 
-    pub fn blind_send<M>(&mut self, message: M::ServerMessage)
+    pub fn blind_send<M>(&self, message: M::ServerMessage)
     where
         M: ClientServerMessage + From<M::ServerMessage> + Serialize,
     {
         let _ = self.send::<M>(message);
     }
 
+    pub fn send_with<M>(
+        &self,
+        message: M::ServerMessage,
+        delivery: Delivery,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.0.send_with(&M::from(message), delivery)
+    }
+
+    pub fn respond<M>(&self, id: RequestId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.0.send_frame(&Frame::Response(id, &M::from(message)))
+    }
+
+    pub fn replicate<M>(&self, state: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.0.send_snapshot(&serde_json::to_value(M::from(state))?)
+    }
+
     pub fn recv<M>(&mut self) -> Result<M::ClientMessage, io::Error>
     where
         M: ClientServerMessage + DeserializeOwned,
         M::ClientMessage: TryFrom<M>,
     {
-        let Ok(message) = self.0.recv::<M>()?.try_into() else {
-            return Err(io::Error::new(
-                ErrorKind::InvalidData,
-                "Received a serverside message from a client",
-            ));
-        };
-        Ok(message)
+        client_message::<M>(self.0.recv::<M>()?)
     }
 }
 
@@ -201,123 +711,992 @@ impl DerefMut for ServersideTransport {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct SocketMessage(SocketAddr);
+#[derive(Debug)]
+struct Session {
+    token: Token,
+    owner: ConnectionId,
+    queue: Option<Vec<Vec<u8>>>,
+    suspended: Option<(SocketAddr, Sender<()>)>,
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    codec: CodecConfig,
+    transports: HashMap<ConnectionId, Arc<ServersideTransport>>,
+    groups: HashMap<String, HashSet<ConnectionId>>,
+    sessions: HashMap<ConnectionId, Session>,
+    closed: HashMap<ConnectionId, DisconnectReason>,
+    admitted: HashSet<ConnectionId>,
+    bans: BanList,
+    recording: Option<Arc<Recording>>,
+    shutting_down: bool,
+}
+
+impl Connections {
+    fn ids(&self) -> Vec<ConnectionId> {
+        let suspended = (self.sessions.iter())
+            .filter(|(_, session)| session.queue.is_some())
+            .map(|(id, _)| id);
+        self.transports.keys().chain(suspended).copied().collect()
+    }
+
+    fn count(&self) -> usize {
+        self.ids().len() + self.admitted.len()
+    }
+
+    fn remove(&mut self, id: ConnectionId) {
+        self.transports.remove(&id);
+        self.sessions.remove(&id);
+        self.closed.remove(&id);
+        self.admitted.remove(&id);
+        self.groups.retain(|_, members| {
+            members.remove(&id);
+            !members.is_empty()
+        });
+    }
+
+    fn is_owner(&self, id: ConnectionId, owner: ConnectionId) -> bool {
+        (self.sessions.get(&id)).is_none_or(|session| session.owner == owner)
+    }
+
+    fn open_session(&mut self, id: ConnectionId, token: Token) {
+        self.sessions.insert(
+            id,
+            Session {
+                token,
+                owner: id,
+                queue: None,
+                suspended: None,
+            },
+        );
+    }
+
+    fn has_session(&self, ticket: SessionTicket) -> bool {
+        (self.sessions.get(&ticket.connection_id))
+            .is_some_and(|session| session.token == ticket.token)
+    }
+
+    fn resume_session(
+        &mut self,
+        ticket: SessionTicket,
+        owner: ConnectionId,
+    ) -> Option<Arc<ServersideTransport>> {
+        let session = self.sessions.get_mut(&ticket.connection_id)?;
+        session.owner = owner;
+        session.suspended = None;
+        session.queue.get_or_insert_default();
+        self.transports.remove(&ticket.connection_id)
+    }
+
+    fn suspend(&mut self, peer: Peer, wake: Sender<()>) {
+        self.transports.remove(&peer.id);
+        self.admitted.remove(&peer.id);
+        if let Some(session) = self.sessions.get_mut(&peer.id) {
+            session.queue.get_or_insert_default();
+            session.suspended = Some((peer.addr, wake));
+        }
+    }
+
+    fn suspended_from(&self, ip: IpAddr) -> Vec<ConnectionId> {
+        (self.sessions.iter())
+            .filter(|(_, session)| {
+                (session.suspended.as_ref())
+                    .is_some_and(|(addr, _)| addr.ip().to_canonical() == ip.to_canonical())
+            })
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    fn attach(
+        &mut self,
+        id: ConnectionId,
+        transport: &Arc<ServersideTransport>,
+    ) -> Result<Vec<Vec<u8>>, io::Error> {
+        if self.shutting_down {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionAborted,
+                "Server is shutting down",
+            ));
+        }
+        self.admitted.remove(&id);
+        let session = self.sessions.get_mut(&id);
+        let queue = session.and_then(|session| session.queue.as_mut());
+        let queued = queue.map(std::mem::take).unwrap_or_default();
+        if queued.is_empty() {
+            if let Some(session) = self.sessions.get_mut(&id) {
+                session.queue = None;
+            }
+            self.transports.insert(id, transport.clone());
+        }
+        Ok(queued)
+    }
+
+    fn close(
+        &mut self,
+        id: ConnectionId,
+        reason: DisconnectReason,
+    ) -> Option<Arc<ServersideTransport>> {
+        let transport = self.transports.remove(&id)?;
+        self.closed.insert(id, reason);
+        Some(transport)
+    }
+
+    fn kick(
+        &mut self,
+        id: ConnectionId,
+        reason: DisconnectReason,
+    ) -> Result<Option<Arc<ServersideTransport>>, io::Error> {
+        if self.transports.contains_key(&id) {
+            Ok(self.close(id, reason))
+        } else if let Some(session) = self.sessions.get_mut(&id) {
+            // wake the thread waiting out the grace period so it reports the kick right away
+            if let Some((_, wake)) = session.suspended.take() {
+                let _ = wake.send(());
+            }
+            self.remove(id);
+            self.closed.insert(id, reason);
+            Ok(None)
+        } else {
+            Err(not_connected(id))
+        }
+    }
+
+    fn shutdown(&mut self) -> Vec<Arc<ServersideTransport>> {
+        self.shutting_down = true;
+        let ids: Vec<_> = self.transports.keys().copied().collect();
+        (ids.into_iter())
+            .filter_map(|id| self.close(id, DisconnectReason::ServerClosing))
+            .collect()
+    }
+
+    fn transport(&self, id: ConnectionId) -> Result<Arc<ServersideTransport>, io::Error> {
+        (self.transports.get(&id).cloned()).ok_or_else(|| not_connected(id))
+    }
+
+    fn writer<M>(
+        &mut self,
+        id: ConnectionId,
+        message: &M,
+        delivery: Delivery,
+    ) -> Result<Option<Arc<ServersideTransport>>, io::Error>
+    where
+        M: Serialize,
+    {
+        if let Some(transport) = self.transports.get(&id) {
+            return Ok(Some(transport.clone()));
+        }
+        let Some(queue) = (self.sessions.get_mut(&id)).and_then(|session| session.queue.as_mut())
+        else {
+            return Err(not_connected(id));
+        };
+        if delivery == Delivery::Reliable {
+            queue.push(self.codec.encode_frame(&Frame::Message(message), None)?);
+        }
+        Ok(None)
+    }
+
+    fn writers<M>(
+        &mut self,
+        recipients: impl IntoIterator<Item = ConnectionId>,
+        message: &M,
+        delivery: Delivery,
+    ) -> Vec<(ConnectionId, Arc<ServersideTransport>)>
+    where
+        M: Serialize,
+    {
+        (recipients.into_iter())
+            .filter_map(|id| match self.writer(id, message, delivery) {
+                Ok(writer) => writer.map(|transport| (id, transport)),
+                Err(err) => {
+                    debug!("unable to send message to {id}: {err}");
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+type SharedConnections = Arc<Mutex<Connections>>;
+
+struct Listener {
+    socket: SocketAddr,
+    tcp: TcpListener,
+    udp: Option<UdpServer>,
+}
+
+impl Listener {
+    fn bind(socket: SocketAddr, config: &ServerConfig) -> Result<Self, io::Error> {
+        let tcp = TcpListener::bind(socket)?;
+        tcp.set_nonblocking(true)?;
+        let socket = tcp.local_addr()?;
+        let udp = (config.udp)
+            .then(|| UdpServer::bind(socket, config.codec.encoding.clone()))
+            .transpose()?;
+        Ok(Listener { socket, tcp, udp })
+    }
+}
 
 pub struct MessageServer {
     listener_threads: Vec<JoinHandle<()>>,
     thread_kills: Vec<Sender<()>>,
+    connections: SharedConnections,
+    local_addrs: Vec<SocketAddr>,
 }
 
 impl MessageServer {
     pub fn start<M>(
-        event_sender: Sender<impl From<(IpAddr, ServerNetworkEvent<M>)> + Send + 'static>,
+        event_sender: Sender<impl From<ServerNetworkEvent<M>> + Send + 'static>,
         port: u16,
-    ) -> Self
+    ) -> Result<Self, io::Error>
     where
         M: ClientServerMessage + DeserializeOwned,
         M::ClientMessage: TryFrom<M>,
     {
-        let (ipv6_thread_kill, ipv6_deathswitch) = channel();
-        let (ipv4_thread_kill, ipv4_deathswitch) = channel();
-        let listener_threads = {
-            vec![
-                {
-                    let event_sender = event_sender.clone();
-                    thread::spawn(move || {
-                        Self::listener_thread::<M>(
-                            event_sender,
-                            ipv6_deathswitch,
-                            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
-                        )
-                    })
-                },
-                thread::spawn(move || {
-                    Self::listener_thread::<M>(
-                        event_sender,
-                        ipv4_deathswitch,
-                        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port),
-                    )
-                }),
-            ]
-        };
-        MessageServer {
-            listener_threads,
-            thread_kills: vec![ipv4_thread_kill, ipv6_thread_kill],
-        }
+        Self::start_with_config::<M>(event_sender, port, ServerConfig::default())
     }
 
-    fn listener_thread<M>(
-        event_sender: Sender<impl From<(IpAddr, ServerNetworkEvent<M>)> + Send + 'static>,
-        deathswitch: Receiver<()>,
-        socket: SocketAddr,
-    ) where
-        M: ClientServerMessage + DeserializeOwned,
+    pub fn start_with_config<M>(
+        event_sender: Sender<impl From<ServerNetworkEvent<M>> + Send + 'static>,
+        port: u16,
+        config: ServerConfig,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ClientMessage: TryFrom<M>,
+    {
+        let sockets = [
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+        ];
+        Self::bind_with_config::<M>(event_sender, &sockets[..], config)
+    }
+
+    pub fn bind<M>(
+        event_sender: Sender<impl From<ServerNetworkEvent<M>> + Send + 'static>,
+        addrs: impl ToSocketAddrs,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned,
         M::ClientMessage: TryFrom<M>,
     {
+        Self::bind_with_config::<M>(event_sender, addrs, ServerConfig::default())
+    }
+
+    pub fn bind_with_config<M>(
+        event_sender: Sender<impl From<ServerNetworkEvent<M>> + Send + 'static>,
+        addrs: impl ToSocketAddrs,
+        config: ServerConfig,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ClientMessage: TryFrom<M>,
+    {
+        if config.udp && config.tls.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "UDP datagrams are not encrypted and cannot be used alongside TLS",
+            ));
+        }
+        let mut listeners: Vec<Listener> = Vec::new();
+        let mut bind_error = None;
+        for mut socket in addrs.to_socket_addrs()? {
+            if socket.port() == 0
+                && let Some(listener) = listeners.first()
+            {
+                socket.set_port(listener.socket.port());
+            }
+            match Listener::bind(socket, &config) {
+                Ok(listener) => listeners.push(listener),
+                Err(err) => {
+                    debug!("unable to listen on {socket}: {err}");
+                    bind_error = Some(err);
+                }
+            }
+        }
+        if listeners.is_empty() {
+            return Err(bind_error.unwrap_or_else(|| {
+                io::Error::new(ErrorKind::InvalidInput, "No addresses to listen on")
+            }));
+        }
+        let local_addrs = listeners.iter().map(|listener| listener.socket).collect();
+        let recording = (config.recording.as_ref())
+            .map(|path| Recording::create::<M>(path, &*config.codec.encoding))
+            .transpose()?;
+        let connections = Arc::new(Mutex::new(Connections {
+            codec: config.codec.clone(),
+            bans: config.bans.clone(),
+            recording,
+            ..Default::default()
+        }));
+        let next_id = Arc::new(AtomicU64::new(1));
+        let (thread_kills, listener_threads) = (listeners.into_iter())
+            .map(|listener| {
+                let (thread_kill, deathswitch) = channel();
+                let event_sender = event_sender.clone();
+                let config = config.clone();
+                let connections = connections.clone();
+                let next_id = next_id.clone();
+                let thread = thread::spawn(move || {
+                    Self::listener_thread::<M>(
+                        event_sender,
+                        deathswitch,
+                        listener,
+                        config,
+                        connections,
+                        next_id,
+                    )
+                });
+                (thread_kill, thread)
+            })
+            .unzip();
+        Ok(MessageServer {
+            listener_threads,
+            thread_kills,
+            connections,
+            local_addrs,
+        })
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn connections(&self) -> Vec<Peer> {
+        let mut peers: Vec<_> = (self.connections.lock().unwrap().transports.iter())
+            .filter_map(|(&id, transport)| {
+                Some(Peer {
+                    id,
+                    addr: transport.peer_addr().ok()?,
+                })
+            })
+            .collect();
+        peers.sort_by_key(|peer| peer.id);
+        peers
+    }
+
+    pub fn stats(&self, id: ConnectionId) -> Option<ConnectionStats> {
+        (self.connections.lock().unwrap().transports.get(&id)).map(|transport| transport.stats())
+    }
+
+    pub fn connection(&self, id: ConnectionId) -> Option<ServersideTransport> {
+        (self.connections.lock().unwrap().transports.get(&id))
+            .and_then(|transport| transport.try_clone().log_and_ok())
+    }
+
+    pub fn send_to<M>(&self, id: ConnectionId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.send_to_with::<M>(id, message, Delivery::Reliable)
+    }
+
+    pub fn send_to_with<M>(
+        &self,
+        id: ConnectionId,
+        message: M::ServerMessage,
+        delivery: Delivery,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let message = M::from(message);
+        let writer = (self.connections.lock().unwrap()).writer(id, &message, delivery)?;
+        match writer {
+            Some(transport) => transport.0.send_with(&message, delivery),
+            None => Ok(()),
+        }
+    }
+
+    pub fn respond<M>(
+        &self,
+        id: ConnectionId,
+        request: RequestId,
+        message: M::ServerMessage,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let transport = self.connections.lock().unwrap().transport(id)?;
+        transport.respond::<M>(request, message)
+    }
+
+    pub fn replicate_to<M>(
+        &self,
+        id: ConnectionId,
+        state: M::ServerMessage,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let transport = self.connections.lock().unwrap().transport(id)?;
+        transport.replicate::<M>(state)
+    }
+
+    pub fn replicate<M>(&self, state: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let state = serde_json::to_value(M::from(state))?;
+        let transports: Vec<_> = (self.connections.lock().unwrap().transports.iter())
+            .map(|(&id, transport)| (id, transport.clone()))
+            .collect();
+        for (id, transport) in transports {
+            (transport.0.send_snapshot(&state))
+                .map_err(|err| debug!("unable to replicate state to {id}: {err}"))
+                .ok();
+        }
+        Ok(())
+    }
+
+    pub fn broadcast<M>(&self, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.broadcast_with::<M>(message, Delivery::Reliable)
+    }
+
+    pub fn broadcast_with<M>(&self, message: M::ServerMessage, delivery: Delivery)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.multicast::<M>(Connections::ids, message, delivery);
+    }
+
+    pub fn broadcast_except<M>(&self, except: ConnectionId, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.broadcast_except_with::<M>(except, message, Delivery::Reliable)
+    }
+
+    pub fn broadcast_except_with<M>(
+        &self,
+        except: ConnectionId,
+        message: M::ServerMessage,
+        delivery: Delivery,
+    ) where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let recipients = |connections: &Connections| {
+            (connections.ids().into_iter())
+                .filter(|&id| id != except)
+                .collect()
+        };
+        self.multicast::<M>(recipients, message, delivery);
+    }
+
+    pub fn kick(&self, id: ConnectionId, reason: impl Into<String>) -> Result<(), io::Error> {
+        let reason = DisconnectReason::Kicked(reason.into());
+        let kicked = self.connections.lock().unwrap().kick(id, reason.clone())?;
+        if let Some(transport) = kicked {
+            transport.0.goodbye(&reason);
+        }
+        Ok(())
+    }
+
+    pub fn ban(&self, ip: IpAddr) {
+        let reason = DisconnectReason::Kicked("banned".to_string());
+        let banned: Vec<_> = {
+            let mut connections = self.connections.lock().unwrap();
+            connections.bans.ban(ip);
+            for id in connections.suspended_from(ip) {
+                let _ = connections.kick(id, reason.clone());
+            }
+            let ids: Vec<_> = (connections.transports.iter())
+                .filter(|(_, transport)| {
+                    (transport.peer_addr())
+                        .is_ok_and(|addr| addr.ip().to_canonical() == ip.to_canonical())
+                })
+                .map(|(&id, _)| id)
+                .collect();
+            (ids.into_iter())
+                .filter_map(|id| connections.close(id, reason.clone()))
+                .collect()
+        };
+        for transport in banned {
+            transport.0.goodbye(&reason);
+        }
+    }
+
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.connections.lock().unwrap().bans.unban(ip)
+    }
+
+    pub fn bans(&self) -> BanList {
+        self.connections.lock().unwrap().bans.clone()
+    }
+
+    pub fn save_bans(&self, persistence: &mut PersistenceManager<BanList>) {
+        *persistence.borrow_mut() = self.bans();
+    }
+
+    pub fn join_group(&self, id: ConnectionId, group: impl Into<String>) -> Result<(), io::Error> {
+        let mut connections = self.connections.lock().unwrap();
+        if !connections.ids().contains(&id) {
+            return Err(not_connected(id));
+        }
+        connections.groups.place(group.into(), id);
+        Ok(())
+    }
+
+    pub fn leave_group(&self, id: ConnectionId, group: &str) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(members) = connections.groups.get_mut(group) {
+            members.remove(&id);
+            if members.is_empty() {
+                connections.groups.remove(group);
+            }
+        }
+    }
+
+    pub fn groups(&self) -> Vec<String> {
+        let mut groups: Vec<_> = (self.connections.lock().unwrap().groups.keys())
+            .cloned()
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    pub fn groups_of(&self, id: ConnectionId) -> Vec<String> {
+        let mut groups: Vec<_> = (self.connections.lock().unwrap().groups.iter())
+            .filter(|(_, members)| members.contains(&id))
+            .map(|(group, _)| group.clone())
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    pub fn group_members(&self, group: &str) -> Vec<ConnectionId> {
+        let mut members: Vec<_> = (self.connections.lock().unwrap().groups.get(group))
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect();
+        members.sort();
+        members
+    }
+
+    pub fn send_to_group<M>(&self, group: &str, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.send_to_group_with::<M>(group, message, Delivery::Reliable)
+    }
+
+    pub fn send_to_group_with<M>(&self, group: &str, message: M::ServerMessage, delivery: Delivery)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let recipients = |connections: &Connections| {
+            (connections.groups.get(group))
+                .into_iter()
+                .flatten()
+                .copied()
+                .collect()
+        };
+        self.multicast::<M>(recipients, message, delivery);
+    }
+
+    fn multicast<M>(
+        &self,
+        recipients: impl FnOnce(&Connections) -> Vec<ConnectionId>,
+        message: M::ServerMessage,
+        delivery: Delivery,
+    ) where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let message = M::from(message);
+        let writers = {
+            let mut connections = self.connections.lock().unwrap();
+            let recipients = recipients(&connections);
+            connections.writers(recipients, &message, delivery)
+        };
+        for (id, transport) in writers {
+            (transport.0.send_with(&message, delivery))
+                .map_err(|err| debug!("unable to send message to {id}: {err}"))
+                .ok();
+        }
+    }
+
+    fn listener_thread<M>(
+        event_sender: Sender<impl From<ServerNetworkEvent<M>> + Send + 'static>,
+        deathswitch: Receiver<()>,
+        listener: Listener,
+        config: ServerConfig,
+        connections: SharedConnections,
+        next_id: Arc<AtomicU64>,
+    ) where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ClientMessage: TryFrom<M>,
+    {
+        let Listener {
+            socket,
+            tcp: listener,
+            udp: udp_server,
+        } = listener;
         debug!("message server starting on socket {socket}");
-        let listener = TcpListener::bind(socket).unwrap();
-        listener.set_nonblocking(true).unwrap();
+        let udp_thread = udp_server.clone().map(|udp_server| {
+            let (udp_thread_kill, udp_deathswitch) = channel();
+            let event_sender = event_sender.clone();
+            let udp_thread =
+                thread::spawn(move || udp_server.server_thread::<M>(event_sender, udp_deathswitch));
+            (udp_thread_kill, udp_thread)
+        });
+        let mut connection_threads: Vec<(Sender<()>, TcpStream, JoinHandle<()>)> = Vec::new();
         while deathswitch.try_recv().is_err() {
+            connection_threads.retain(|(_, _, thread)| !thread.is_finished());
             match listener.accept() {
                 Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                    thread::sleep(Duration::from_millis(100));
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
                 }
-                Ok((stream, socket)) => {
-                    debug!("new client connected: {socket}");
+                Ok((stream, addr)) => {
+                    debug!("new client connected: {addr}");
                     let event_sender = event_sender.clone();
-                    stream.set_nonblocking(false).unwrap();
-                    let mut transport = ServersideTransport::new(stream);
-                    let _ = transport.0.send(&SocketMessage(socket));
-                    let Ok(SocketMessage(my_socket_addr)) = transport.0.recv() else {
-                        error!("Expected socket response from client");
+                    let stream_handle: Result<_, io::Error> = try {
+                        stream.set_nonblocking(false)?;
+                        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                        stream.try_clone()?
+                    };
+                    let Some(stream_handle) = stream_handle.log_and_ok() else {
                         continue;
                     };
-                    {
-                        let transport = transport.try_clone().unwrap();
-                        event_sender
-                            .send(
-                                ((socket.ip(), NetworkEvent::Connect {
-                                    transport,
-                                    my_socket_addr,
-                                }))
-                                    .into(),
-                            )
-                            .unwrap();
-                    }
-                    thread::spawn(move || {
-                        Self::connection_thread::<M>(event_sender, transport, socket)
+                    let peer = Peer {
+                        id: ConnectionId(next_id.fetch_add(1, Ordering::Relaxed)),
+                        addr,
+                    };
+                    let transport = ServersideTransport::new(stream);
+                    let udp_server = udp_server.clone();
+                    let config = config.clone();
+                    let connections = connections.clone();
+                    let (thread_kill, deathswitch) = channel();
+                    let wake = thread_kill.clone();
+                    let thread = thread::spawn(move || {
+                        Self::connection_thread::<M>(
+                            event_sender,
+                            transport,
+                            peer,
+                            udp_server,
+                            config,
+                            connections,
+                            (wake, deathswitch),
+                        )
                     });
+                    connection_threads.push((thread_kill, stream_handle, thread));
                 }
                 Err(e) => {
-                    error!("Message server listener error: {e}");
-                    panic!();
+                    error!("Message server listener error on {socket}: {e}");
+                    let _ = event_sender.send(ServerEvent::ListenerError(e).into());
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
                 }
             }
         }
         debug!("message server shutting down");
+        let closed = connections.lock().unwrap().shutdown();
+        for transport in closed {
+            transport.0.goodbye(&DisconnectReason::ServerClosing);
+        }
+        for (thread_kill, stream, thread) in connection_threads {
+            let _ = thread_kill.send(());
+            let _ = stream.shutdown(Shutdown::Both);
+            thread.join().unwrap();
+        }
+        if let Some((udp_thread_kill, udp_thread)) = udp_thread {
+            udp_thread_kill.send(()).log_and_ignore();
+            udp_thread.join().unwrap();
+        }
+    }
+
+    fn handshake<M>(
+        transport: &mut ServersideTransport,
+        peer: &mut Peer,
+        udp_server: Option<&UdpServer>,
+        config: &ServerConfig,
+        connections: &SharedConnections,
+    ) -> Result<Result<(Hello, bool), RejectReason>, io::Error>
+    where
+        M: ClientServerMessage,
+    {
+        transport.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        if let Some(tls) = &config.tls {
+            transport.0.tls = Some(tls.accept(&transport.0.stream)?);
+        }
+        let mut server_hello = Hello::new::<M>(peer.addr, &*config.codec.encoding);
+        server_hello.compression = config.compression.algorithms.clone();
+        transport.0.send_handshake(&server_hello)?;
+        let new_session_token = (config.session_grace)
+            .map(|_| Token::generate())
+            .transpose()?;
+        let client_hello = match transport.0.recv_handshake::<Hello>() {
+            Ok(client_hello) => Ok(client_hello),
+            Err(err) if err.kind() == ErrorKind::InvalidData => Err(RejectReason::InvalidHandshake),
+            Err(err) => return Err(err),
+        };
+        let verdict = client_hello.and_then(|client_hello| {
+            let protocol_version = Hello::negotiate(&server_hello, &client_hello)?;
+            Ok((client_hello, protocol_version))
+        });
+        let verdict = verdict.and_then(|(client_hello, protocol_version)| {
+            let admission = Admission {
+                peer: *peer,
+                protocol_id: client_hello.protocol_id().to_string(),
+                protocol_version,
+                resuming: client_hello.resume.is_some(),
+            };
+            Self::admit(&admission, config, connections)?;
+            Ok((client_hello, protocol_version))
+        });
+        let mut stale_transport = None;
+        let verdict = verdict.and_then(|(client_hello, protocol_version)| {
+            let mut connections = connections.lock().unwrap();
+            let resume = (client_hello.resume).filter(|&ticket| {
+                config.session_grace.is_some() && connections.has_session(ticket)
+            });
+            if resume.is_none()
+                && (config.max_connections).is_some_and(|max| connections.count() >= max)
+            {
+                return Err(RejectReason::ServerFull);
+            }
+            let session = match (config.session_grace, resume) {
+                (Some(_), Some(ticket)) => {
+                    stale_transport = connections.resume_session(ticket, peer.id);
+                    peer.id = ticket.connection_id;
+                    (Some(ticket.token), true)
+                }
+                (Some(_), None) => {
+                    let token = new_session_token.unwrap();
+                    connections.open_session(peer.id, token);
+                    (Some(token), false)
+                }
+                (None, _) => (None, false),
+            };
+            if !session.1 {
+                connections.admitted.insert(peer.id);
+            }
+            Ok((client_hello, protocol_version, session))
+        });
+        if let Some(stale_transport) = stale_transport {
+            stale_transport.shutdown().log_and_ignore();
+        }
+        let (client_hello, protocol_version, (session_token, resumed)) = match verdict {
+            Ok(accepted) => accepted,
+            Err(reason) => {
+                transport
+                    .0
+                    .send_handshake::<Verdict>(&Err(reason.clone()))?;
+                return Ok(Err(reason));
+            }
+        };
+        transport.0.protocol_version = protocol_version;
+        transport.0.connection_id = peer.id;
+        transport.0.recorder = (connections.lock().unwrap().recording.as_ref())
+            .map(|recording| recording.recorder(*peer));
+        transport.0.udp = (udp_server.map(|udp_server| {
+            let recorder = transport.0.recorder.clone();
+            udp_server.register(*peer, transport.0.stats.clone(), recorder)
+        }))
+        .transpose()?;
+        let compression = config.compression.negotiate(&client_hello.compression);
+        let welcome = Welcome {
+            protocol_version,
+            connection_id: peer.id,
+            udp_token: transport.0.udp.as_ref().map(UdpChannel::token),
+            session_token,
+            resumed,
+            compression,
+        };
+        transport.0.send_handshake::<Verdict>(&Ok(welcome))?;
+        transport.0.codec = config.codec.clone();
+        transport.0.compressor = config.compression.compressor(compression);
+        Ok(Ok((client_hello, resumed)))
+    }
+
+    fn admit(
+        admission: &Admission,
+        config: &ServerConfig,
+        connections: &SharedConnections,
+    ) -> Result<(), RejectReason> {
+        let ip = admission.peer.addr.ip();
+        if connections.lock().unwrap().bans.is_banned(ip) {
+            return Err(RejectReason::Banned);
+        }
+        if let Some(hook) = &config.admission {
+            hook.check(admission).map_err(RejectReason::Refused)?;
+        }
+        Ok(())
     }
 
     fn connection_thread<M>(
-        event_sender: Sender<impl From<(IpAddr, ServerNetworkEvent<M>)> + Send + 'static>,
+        event_sender: Sender<impl From<ServerNetworkEvent<M>> + Send + 'static>,
         mut transport: ServersideTransport,
-        socket: SocketAddr,
+        mut peer: Peer,
+        udp_server: Option<UdpServer>,
+        config: ServerConfig,
+        connections: SharedConnections,
+        (wake, deathswitch): (Sender<()>, Receiver<()>),
     ) where
         M: ClientServerMessage + DeserializeOwned,
         M::ClientMessage: TryFrom<M>,
     {
-        let src_addr = socket.ip();
-        let send_event = |event: ServerNetworkEvent<M>| event_sender.send((src_addr, event).into());
-        let Err(err): Result<(), io::Error> = (try {
+        let accepted_id = peer.id;
+        let handshake = Self::handshake::<M>(
+            &mut transport,
+            &mut peer,
+            udp_server.as_ref(),
+            &config,
+            &connections,
+        );
+        let send_event = |event: NetworkEvent<ServersideTransport, M::ClientMessage>| {
+            event_sender.send(ServerEvent::Connection(peer, event).into())
+        };
+        let unregister_udp = |transport: &ServersideTransport| {
+            if let (Some(udp_server), Some(udp)) = (&udp_server, &transport.0.udp) {
+                udp_server.unregister(udp.token());
+            }
+        };
+        let (client_hello, resumed) = match handshake {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(reason)) => {
+                debug!("rejected client {peer}: {reason}");
+                let _ = (send_event)(NetworkEvent::Rejected(reason));
+                return;
+            }
+            Err(err) => {
+                unregister_udp(&transport);
+                error!("Handshake with client {peer} failed: {err}");
+                let mut connections = connections.lock().unwrap();
+                if connections.is_owner(peer.id, accepted_id) {
+                    connections.remove(peer.id);
+                    if peer.id != accepted_id {
+                        let reason = DisconnectReason::from_error(&err);
+                        let _ = (send_event)(NetworkEvent::Disconnect(reason));
+                    }
+                }
+                return;
+            }
+        };
+        let session: Result<DisconnectReason, io::Error> = try {
+            if let Some(conditions) = config.conditions {
+                transport.0.simulate(conditions)?;
+            }
+            transport.set_read_timeout(Some(config.heartbeat.timeout))?;
+            let _heartbeat =
+                HeartbeatThread::spawn(transport.0.try_clone()?, config.heartbeat.interval);
+            let writer = Arc::new(transport.try_clone()?);
+            // messages sent while the queue replays keep queueing until it drains
+            loop {
+                let queued = (connections.lock().unwrap().attach(peer.id, &writer))
+                    .inspect_err(|_| writer.0.goodbye(&DisconnectReason::ServerClosing))?;
+                if queued.is_empty() {
+                    break;
+                }
+                for frame in queued {
+                    writer.0.write_all(&frame)?;
+                    writer.0.stats.sent(FrameKind::Message, frame.len());
+                }
+            }
+            if let Some(recorder) = &transport.0.recorder {
+                if resumed {
+                    recorder.resumed();
+                } else {
+                    recorder.connect(client_hello.socket);
+                }
+            }
+            {
+                let transport = transport.try_clone()?;
+                (send_event)(if resumed {
+                    NetworkEvent::Resumed { transport }
+                } else {
+                    NetworkEvent::Connect {
+                        transport,
+                        my_socket_addr: client_hello.socket,
+                    }
+                })
+                .map_err(|_| io::Error::new(ErrorKind::ConnectionAborted, "Channel closed"))?;
+            }
             loop {
-                (send_event)(NetworkEvent::Message(transport.recv::<M>()?))
+                let event = match transport.0.recv_incoming::<M>()? {
+                    Incoming::Message(message) => NetworkEvent::Message(client_message(message)?),
+                    Incoming::Request(id, message) => NetworkEvent::Request {
+                        id,
+                        message: client_message(message)?,
+                    },
+                    Incoming::Response(id, _) => {
+                        debug!("ignoring response to request {id} from client {peer}");
+                        continue;
+                    }
+                    Incoming::State(_) => {
+                        debug!("ignoring replicated state from client {peer}");
+                        continue;
+                    }
+                    Incoming::Goodbye(reason) => break reason,
+                };
+                (send_event)(event)
                     .map_err(|_| io::Error::new(ErrorKind::ConnectionAborted, "Channel closed"))?;
             }
-        }) else {
+        };
+        unregister_udp(&transport);
+        let mut connections_lock = connections.lock().unwrap();
+        if !connections_lock.is_owner(peer.id, accepted_id) {
+            debug!("connection with {peer} was taken over by a resumed session");
+            return;
+        }
+        let reason = match session {
+            Ok(reason) => reason,
+            Err(err) => match connections_lock.closed.remove(&peer.id) {
+                Some(reason) => reason,
+                None if connections_lock.shutting_down => DisconnectReason::ServerClosing,
+                None => {
+                    let reason = DisconnectReason::from_error(&err);
+                    if reason.is_protocol_error() {
+                        transport.0.goodbye(&reason);
+                    }
+                    reason
+                }
+            },
+        };
+        let _ = transport.shutdown();
+        let grace = (config.session_grace)
+            .filter(|_| matches!(reason, DisconnectReason::ConnectionLost(_)));
+        let Some(grace) = grace else {
+            connections_lock.remove(peer.id);
+            drop(connections_lock);
+            debug!("connection ended with {peer}: {reason}");
+            if let Some(recorder) = &transport.0.recorder {
+                recorder.disconnect(&reason);
+            }
+            let _ = (send_event)(NetworkEvent::Disconnect(reason));
             return;
         };
-        debug!("connection ended with {socket}: {err}");
-        let _ = (send_event)(NetworkEvent::Disconnect);
+        connections_lock.suspend(peer, wake);
+        drop(connections_lock);
+        debug!("connection with {peer} suspended: {reason}");
+        if let Some(recorder) = &transport.0.recorder {
+            recorder.suspended();
+        }
+        let _ = (send_event)(NetworkEvent::Suspended);
+        let reason = match deathswitch.recv_timeout(grace) {
+            Err(RecvTimeoutError::Timeout) => DisconnectReason::SessionExpired,
+            _ => DisconnectReason::ServerClosing,
+        };
+        let mut connections = connections.lock().unwrap();
+        let expired = (connections.sessions.get(&peer.id))
+            .is_some_and(|session| session.owner == accepted_id && session.queue.is_some());
+        let reason = if expired {
+            connections.remove(peer.id);
+            reason
+        } else if !connections.sessions.contains_key(&peer.id)
+            && let Some(reason) = connections.closed.remove(&peer.id)
+        {
+            reason
+        } else {
+            return;
+        };
+        drop(connections);
+        debug!("session with {peer} ended: {reason}");
+        if let Some(recorder) = &transport.0.recorder {
+            recorder.disconnect(&reason);
+        }
+        let _ = (send_event)(NetworkEvent::Disconnect(reason));
     }
 }
 
@@ -327,14 +1706,26 @@ impl Drop for MessageServer {
             killswitch.send(()).log_and_ignore();
         }
         while let Some(thread) = self.listener_threads.pop() {
-            thread.join().unwrap();
+            if thread.join().is_err() {
+                error!("Message server listener thread panicked");
+            }
         }
     }
 }
 
+#[derive(Debug, Default)]
+struct ClientConnection {
+    stream: Option<TcpStream>,
+    transport: Option<ClientsideTransport>,
+    closed: bool,
+}
+
+type SharedClientConnection = Arc<Mutex<ClientConnection>>;
+
 pub struct MessageClient {
     connection_thread: Option<JoinHandle<()>>,
     thread_kill: Sender<()>,
+    connection: SharedClientConnection,
 }
 
 impl MessageClient {
@@ -343,68 +1734,784 @@ impl MessageClient {
         socket: SocketAddr,
     ) -> Self
     where
-        M: ClientServerMessage + DeserializeOwned,
+        M: ClientServerMessage + DeserializeOwned + Send + 'static,
+        M::ServerMessage: TryFrom<M>,
+    {
+        Self::start_with_config::<M>(event_sender, socket, ClientConfig::default())
+    }
+
+    pub fn start_with_config<M>(
+        event_sender: Sender<impl From<ClientNetworkEvent<M>> + Send + 'static>,
+        socket: SocketAddr,
+        config: ClientConfig,
+    ) -> Self
+    where
+        M: ClientServerMessage + DeserializeOwned + Send + 'static,
         M::ServerMessage: TryFrom<M>,
     {
         let (thread_kill, deathswitch) = channel();
-        let connection_thread = Some(thread::spawn(move || {
-            Self::connection_thread::<M>(event_sender, socket, deathswitch)
+        let connection = SharedClientConnection::default();
+        let connection_thread = Some(thread::spawn({
+            let connection = connection.clone();
+            move || {
+                Self::connection_thread::<M>(event_sender, socket, deathswitch, config, connection)
+            }
         }));
         MessageClient {
             connection_thread,
             thread_kill,
+            connection,
         }
     }
 
+    pub fn stats(&self) -> Option<ConnectionStats> {
+        (self.connection.lock().unwrap().transport.as_ref()).map(|transport| transport.stats())
+    }
+
+    fn handshake<M>(
+        transport: &mut ClientsideTransport,
+        socket: SocketAddr,
+        config: &ClientConfig,
+        session: Option<SessionTicket>,
+    ) -> Result<Result<(Hello, Welcome), RejectReason>, io::Error>
+    where
+        M: ClientServerMessage,
+    {
+        transport.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        if let Some(tls) = &config.tls {
+            transport.0.tls = Some(tls.connect(&transport.0.stream, socket)?);
+        }
+        let server_hello = match transport.0.recv_handshake::<Hello>() {
+            Ok(server_hello) if server_hello.is_valid() => server_hello,
+            Ok(_) => return Ok(Err(RejectReason::InvalidHandshake)),
+            Err(err) if err.kind() == ErrorKind::InvalidData => {
+                return Ok(Err(RejectReason::InvalidHandshake));
+            }
+            Err(err) => return Err(err),
+        };
+        let mut hello = Hello::new::<M>(socket, &*config.codec.encoding);
+        hello.resume = session;
+        hello.compression = config.compression.algorithms.clone();
+        transport.0.send_handshake(&hello)?;
+        let verdict = match transport.0.recv_handshake::<Verdict>() {
+            Ok(verdict) => verdict,
+            Err(err) if err.kind() == ErrorKind::InvalidData => Err(RejectReason::InvalidHandshake),
+            Err(err) => return Err(err),
+        };
+        Ok(verdict.map(|welcome| (server_hello, welcome)))
+    }
+
     fn connection_thread<M>(
         event_sender: Sender<impl From<ClientNetworkEvent<M>> + Send + 'static>,
         socket: SocketAddr,
         deathswitch: Receiver<()>,
+        config: ClientConfig,
+        shared_connection: SharedClientConnection,
     ) where
-        M: ClientServerMessage + DeserializeOwned,
+        M: ClientServerMessage + DeserializeOwned + Send + 'static,
         M::ServerMessage: TryFrom<M>,
     {
+        let send_event = |event: NetworkEvent<ClientsideTransport, M::ServerMessage>| {
+            event_sender.send(ClientEvent::Connection(event).into())
+        };
+        let recording = (config.recording.as_ref()).and_then(|path| {
+            (Recording::create::<M>(path, &*config.codec.encoding))
+                .map_err(|err| error!("Unable to record session to {}: {err}", path.display()))
+                .ok()
+        });
+        let udp = config.udp && config.tls.is_none();
+        if config.udp && !udp {
+            error!(
+                "UDP datagrams are not encrypted; not opening a udp channel to {socket} over TLS"
+            );
+        }
+        let mut attempt = 0;
+        let mut session = None;
         while deathswitch.try_recv().is_err() {
-            let _: Result<_, io::Error> = try {
-                let stream = TcpStream::connect(socket)?;
+            let connection: Result<_, io::Error> = try {
+                let stream = TcpStream::connect_timeout(&socket, CONNECT_TIMEOUT)?;
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
                 debug!("connected to {socket}");
-                let Err(err): Result<_, io::Error> = try {
-                    let mut transport = ClientsideTransport::new(stream);
+                {
+                    let mut shared_connection = shared_connection.lock().unwrap();
+                    if shared_connection.closed {
+                        return;
+                    }
+                    shared_connection.stream = Some(stream.try_clone()?);
+                }
+                let mut transport = ClientsideTransport::new(stream);
+                let handshake = Self::handshake::<M>(&mut transport, socket, &config, session);
+                let (server_hello, welcome) = match handshake {
+                    Ok(Ok(accepted)) => accepted,
+                    Ok(Err(reason)) => {
+                        error!("Rejected by server {socket}: {reason}");
+                        let _ = send_event(NetworkEvent::Rejected(reason));
+                        return;
+                    }
+                    Err(err) => Err(io::Error::new(
+                        err.kind(),
+                        format!("Handshake failed: {err}"),
+                    ))?,
+                };
+                attempt = 0;
+                session = (welcome.session_token).map(|token| SessionTicket {
+                    connection_id: welcome.connection_id,
+                    token,
+                });
+                transport.0.protocol_version = welcome.protocol_version;
+                transport.0.connection_id = welcome.connection_id;
+                transport.0.codec = config.codec.clone();
+                transport.0.compressor = config.compression.compressor(welcome.compression);
+                transport.0.recorder = recording.as_ref().map(|recording| {
+                    recording.recorder(Peer {
+                        id: welcome.connection_id,
+                        addr: socket,
+                    })
+                });
+                transport.0.udp = welcome
+                    .udp_token
+                    .filter(|_| udp)
+                    .map(|token| {
+                        let (stats, recorder) =
+                            (transport.0.stats.clone(), transport.0.recorder.clone());
+                        UdpChannel::connect(
+                            socket,
+                            token,
+                            config.codec.encoding.clone(),
+                            stats,
+                            recorder,
+                        )
+                    })
+                    .transpose()
+                    .map_err(|e| error!("Unable to open udp channel to {socket}: {e}"))
+                    .ok()
+                    .flatten();
+                if let Some(conditions) = config.conditions {
+                    transport.0.simulate(conditions)?;
+                }
+                shared_connection.lock().unwrap().transport = Some(transport.try_clone()?);
+                let udp_thread = transport.0.udp.clone().map(|udp| {
+                    let (udp_thread_kill, udp_deathswitch) = channel();
+                    let event_sender = event_sender.clone();
+                    let udp_thread = thread::spawn(move || {
+                        udp.client_thread::<M>(event_sender, udp_deathswitch)
+                    });
+                    (udp_thread_kill, udp_thread)
+                });
+                let session_result: Result<_, io::Error> = try {
+                    transport.set_read_timeout(Some(config.heartbeat.timeout))?;
+                    let _heartbeat =
+                        HeartbeatThread::spawn(transport.0.try_clone()?, config.heartbeat.interval);
+                    if let Some(recorder) = &transport.0.recorder {
+                        if welcome.resumed {
+                            recorder.resumed();
+                        } else {
+                            recorder.connect(server_hello.socket);
+                        }
+                    }
                     {
-                        let mut transport = transport.try_clone().unwrap();
-                        let Ok(SocketMessage(my_socket_addr)) = transport.0.recv() else {
-                            error!("Expected socket message from server");
-                            continue;
+                        let transport = transport.try_clone().unwrap();
+                        let event = if welcome.resumed {
+                            NetworkEvent::Resumed { transport }
+                        } else {
+                            NetworkEvent::Connect {
+                                transport,
+                                my_socket_addr: server_hello.socket,
+                            }
                         };
-                        let _ = transport.0.send(&SocketMessage(socket));
-                        event_sender
-                            .send(
-                                NetworkEvent::Connect {
-                                    transport,
-                                    my_socket_addr,
-                                }
-                                .into(),
-                            )
-                            .unwrap();
+                        send_event(event).map_err(|_| {
+                            io::Error::new(ErrorKind::ConnectionAborted, "Channel Closed")
+                        })?;
                     }
                     loop {
-                        event_sender
-                            .send(NetworkEvent::Message(transport.recv::<M>()?).into())
-                            .map_err(|_| {
-                                io::Error::new(ErrorKind::ConnectionAborted, "Channel Closed")
-                            })?;
+                        let event = match transport.0.recv_incoming::<M>()? {
+                            Incoming::Message(message) => {
+                                NetworkEvent::Message(server_message(message)?)
+                            }
+                            Incoming::State(state) => NetworkEvent::State(server_message(state)?),
+                            Incoming::Response(id, message) => {
+                                if !transport.0.requests.resolve(id, message) {
+                                    debug!("discarding response to expired request {id}");
+                                }
+                                continue;
+                            }
+                            Incoming::Request(id, _) => {
+                                debug!("ignoring request {id} from server {socket}");
+                                continue;
+                            }
+                            Incoming::Goodbye(reason) => break reason,
+                        };
+                        send_event(event).map_err(|_| {
+                            io::Error::new(ErrorKind::ConnectionAborted, "Channel Closed")
+                        })?;
+                    }
+                };
+                let closed = {
+                    let mut shared_connection = shared_connection.lock().unwrap();
+                    shared_connection.stream = None;
+                    shared_connection.transport = None;
+                    shared_connection.closed
+                };
+                let reason = match session_result {
+                    Ok(reason) => reason,
+                    Err(_) if closed => DisconnectReason::ClientClosing,
+                    Err(err) => {
+                        let reason = DisconnectReason::from_error(&err);
+                        if reason.is_protocol_error() {
+                            transport.0.goodbye(&reason);
+                        }
+                        reason
                     }
                 };
-                debug!("connection ended with {socket}: {err}");
-                let _ = event_sender.send(NetworkEvent::Disconnect.into());
+                let _ = transport.shutdown();
+                transport.0.requests.clear();
+                if let Some((udp_thread_kill, udp_thread)) = udp_thread {
+                    udp_thread_kill.send(()).log_and_ignore();
+                    udp_thread.join().unwrap();
+                }
+                debug!("connection ended with {socket}: {reason}");
+                if let Some(recorder) = &transport.0.recorder {
+                    recorder.disconnect(&reason);
+                }
+                let _ = send_event(NetworkEvent::Disconnect(reason.clone()));
+                match reason {
+                    DisconnectReason::ConnectionLost(_) => {}
+                    DisconnectReason::ServerClosing => session = None,
+                    _ => return,
+                }
             };
+            if let Err(err) = connection {
+                debug!("unable to connect to {socket}: {err}");
+            }
+            if shared_connection.lock().unwrap().closed {
+                return;
+            }
+            attempt += 1;
+            let Some(delay) = config.reconnect.delay(attempt) else {
+                debug!("giving up on connecting to {socket}");
+                let _ = event_sender.send(ClientEvent::GaveUp.into());
+                return;
+            };
+            debug!("reconnecting to {socket} in {delay:?} (attempt {attempt})");
+            if event_sender
+                .send(ClientEvent::Reconnecting { attempt, delay }.into())
+                .is_err()
+            {
+                return;
+            }
+            if deathswitch.recv_timeout(delay) != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
         }
     }
 }
 
 impl Drop for MessageClient {
     fn drop(&mut self) {
-        self.thread_kill.send(()).unwrap();
+        {
+            let mut connection = self.connection.lock().unwrap();
+            connection.closed = true;
+            if let Some(transport) = connection.transport.take() {
+                transport.0.goodbye(&DisconnectReason::ClientClosing);
+            } else if let Some(stream) = connection.stream.take() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        let _ = self.thread_kill.send(());
         self.connection_thread.take().unwrap().join().unwrap();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::ErrorKind,
+        net::{Ipv4Addr, SocketAddr, TcpListener},
+        sync::{
+            Arc,
+            mpsc::{Receiver, channel},
+        },
+        thread,
+        time::Duration,
+    };
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{
+        ClientConfig, ClientEvent, ClientNetworkEvent, ClientServerMessage, ClientsideTransport,
+        CodecConfig, DisconnectReason, FrameKind, HeartbeatConfig, Json, MessageClient,
+        MessagePack, MessageServer, NetworkEvent, Peer, ReconnectPolicy, RejectReason,
+        ServerConfig, ServerEvent, ServerNetworkEvent, TlsClient, TlsServer,
+    };
+
+    pub(super) const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(super) enum Message {
+        Client(String),
+        Server(u32),
+    }
+
+    impl ClientServerMessage for Message {
+        type ClientMessage = String;
+        type ServerMessage = u32;
+    }
+
+    impl From<String> for Message {
+        fn from(message: String) -> Self {
+            Message::Client(message)
+        }
+    }
+
+    impl From<u32> for Message {
+        fn from(message: u32) -> Self {
+            Message::Server(message)
+        }
+    }
+
+    impl TryFrom<Message> for String {
+        type Error = ();
+
+        fn try_from(message: Message) -> Result<Self, Self::Error> {
+            match message {
+                Message::Client(message) => Ok(message),
+                _ => Err(()),
+            }
+        }
+    }
+
+    impl TryFrom<Message> for u32 {
+        type Error = ();
+
+        fn try_from(message: Message) -> Result<Self, Self::Error> {
+            match message {
+                Message::Server(message) => Ok(message),
+                _ => Err(()),
+            }
+        }
+    }
+
+    fn unused_port() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn test_reconnect_gives_up() {
+        let (client_sender, client_events) = channel::<ClientNetworkEvent<Message>>();
+        let config = ClientConfig {
+            reconnect: ReconnectPolicy::Backoff {
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(15),
+                multiplier: 2.0,
+                jitter: 0.0,
+                max_attempts: Some(2),
+            },
+            ..Default::default()
+        };
+        let _client =
+            MessageClient::start_with_config::<Message>(client_sender, unused_port(), config);
+        let delays: Vec<_> = (1..=2)
+            .map(|expected| match client_events.recv_timeout(TIMEOUT) {
+                Ok(ClientEvent::Reconnecting { attempt, delay }) if attempt == expected => delay,
+                event => panic!("expected reconnect attempt {expected}, got {event:?}"),
+            })
+            .collect();
+        assert_eq!(delays, [10, 15].map(Duration::from_millis));
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::GaveUp)
+        ));
+    }
+
+    pub(super) fn start_server(
+        config: ServerConfig,
+    ) -> (MessageServer, Receiver<ServerNetworkEvent<Message>>) {
+        let (server_sender, server_events) = channel();
+        let server = MessageServer::bind_with_config::<Message>(
+            server_sender,
+            (Ipv4Addr::LOCALHOST, 0),
+            config,
+        )
+        .unwrap();
+        (server, server_events)
+    }
+
+    pub(super) fn start_client(
+        server: &MessageServer,
+        config: ClientConfig,
+    ) -> (MessageClient, Receiver<ClientNetworkEvent<Message>>) {
+        let (client_sender, client_events) = channel();
+        let client = MessageClient::start_with_config::<Message>(
+            client_sender,
+            server.local_addrs()[0],
+            config,
+        );
+        (client, client_events)
+    }
+
+    pub(super) fn accept(
+        server_events: &Receiver<ServerNetworkEvent<Message>>,
+        client_events: &Receiver<ClientNetworkEvent<Message>>,
+    ) -> (Peer, ClientsideTransport) {
+        let Ok(ServerEvent::Connection(peer, NetworkEvent::Connect { .. })) =
+            server_events.recv_timeout(TIMEOUT)
+        else {
+            panic!("server did not see the client connect");
+        };
+        let Ok(ClientEvent::Connection(NetworkEvent::Connect { transport, .. })) =
+            client_events.recv_timeout(TIMEOUT)
+        else {
+            panic!("client did not connect");
+        };
+        (peer, transport)
+    }
+
+    #[test]
+    fn test_session_resume() {
+        let (server, server_events) = start_server(ServerConfig {
+            session_grace: Some(TIMEOUT),
+            ..Default::default()
+        });
+        let (_client, client_events) = start_client(
+            &server,
+            ClientConfig {
+                reconnect: ReconnectPolicy::Backoff {
+                    initial_delay: Duration::from_millis(300),
+                    max_delay: Duration::from_millis(300),
+                    multiplier: 1.0,
+                    jitter: 0.0,
+                    max_attempts: None,
+                },
+                ..Default::default()
+            },
+        );
+        let (peer, transport) = accept(&server_events, &client_events);
+
+        transport.shutdown().unwrap();
+        assert!(matches!(
+            server_events.recv_timeout(TIMEOUT),
+            Ok(ServerEvent::Connection(suspended, NetworkEvent::Suspended)) if suspended == peer
+        ));
+        for message in [1, 2] {
+            server.send_to::<Message>(peer.id, message).unwrap();
+        }
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::Connection(NetworkEvent::Disconnect(
+                DisconnectReason::ConnectionLost(_)
+            )))
+        ));
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::Reconnecting { attempt: 1, .. })
+        ));
+
+        let Ok(ClientEvent::Connection(NetworkEvent::Resumed { transport })) =
+            client_events.recv_timeout(TIMEOUT)
+        else {
+            panic!("client did not resume its session");
+        };
+        assert_eq!(transport.connection_id(), peer.id);
+        assert!(matches!(
+            server_events.recv_timeout(TIMEOUT),
+            Ok(ServerEvent::Connection(resumed, NetworkEvent::Resumed { .. }))
+                if resumed.id == peer.id
+        ));
+        for expected in [1, 2] {
+            assert!(matches!(
+                client_events.recv_timeout(TIMEOUT),
+                Ok(ClientEvent::Connection(NetworkEvent::Message(message))) if message == expected
+            ));
+        }
+        assert_eq!(server.connections().len(), 1);
+        let stats = server.stats(peer.id).unwrap();
+        assert_eq!(stats.frames[&FrameKind::Message].sent, 2);
+    }
+
+    #[test]
+    fn test_transfer_stats() {
+        let heartbeat = HeartbeatConfig {
+            interval: Duration::from_millis(10),
+            timeout: TIMEOUT,
+        };
+        let (server, server_events) = start_server(ServerConfig {
+            heartbeat,
+            ..Default::default()
+        });
+        let (_client, client_events) = start_client(&server, Default::default());
+        let (peer, transport) = accept(&server_events, &client_events);
+
+        transport.send::<Message>("hello".to_string()).unwrap();
+        assert!(matches!(
+            server_events.recv_timeout(TIMEOUT),
+            Ok(ServerEvent::Connection(_, NetworkEvent::Message(_)))
+        ));
+        thread::sleep(Duration::from_millis(100));
+        let stats = server.stats(peer.id).unwrap();
+        assert_eq!(stats.messages_sent, 0);
+        assert_eq!(stats.messages_received, 1);
+        assert_eq!(stats.frames[&FrameKind::Message].received, 1);
+        assert!(stats.frames[&FrameKind::Handshake].sent > 0);
+        assert!(stats.frames[&FrameKind::Ping].sent > 0);
+        assert!(stats.frames[&FrameKind::Pong].received > 0);
+    }
+
+    #[test]
+    fn test_rpc() {
+        let (server, server_events) = start_server(ServerConfig::default());
+        let (_client, client_events) = start_client(&server, ClientConfig::default());
+        let (peer, transport) = accept(&server_events, &client_events);
+
+        let handle = (transport.request::<Message>("ask".to_string(), TIMEOUT)).unwrap();
+        let Ok(ServerEvent::Connection(_, NetworkEvent::Request { id, message })) =
+            server_events.recv_timeout(TIMEOUT)
+        else {
+            panic!("server did not receive the request");
+        };
+        assert_eq!((id, message.as_str()), (handle.id(), "ask"));
+        server.send_to::<Message>(peer.id, 1).unwrap();
+        server.respond::<Message>(peer.id, id, 2).unwrap();
+        assert_eq!(handle.wait().unwrap(), 2);
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::Connection(NetworkEvent::Message(1)))
+        ));
+
+        let timeout = Duration::from_millis(100);
+        let handle = (transport.request::<Message>("ignored".to_string(), timeout)).unwrap();
+        let Ok(ServerEvent::Connection(_, NetworkEvent::Request { id, .. })) =
+            server_events.recv_timeout(TIMEOUT)
+        else {
+            panic!("server did not receive the request");
+        };
+        assert_eq!(handle.wait().unwrap_err().kind(), ErrorKind::TimedOut);
+        server.respond::<Message>(peer.id, id, 3).unwrap();
+        server.send_to::<Message>(peer.id, 4).unwrap();
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::Connection(NetworkEvent::Message(4)))
+        ));
+    }
+
+    #[test]
+    fn test_kick_and_ban_suspended() {
+        let (server, server_events) = start_server(ServerConfig {
+            session_grace: Some(Duration::from_secs(60)),
+            ..Default::default()
+        });
+        let suspend = || {
+            let (client, client_events) = start_client(
+                &server,
+                ClientConfig {
+                    reconnect: ReconnectPolicy::Never,
+                    ..Default::default()
+                },
+            );
+            let (peer, transport) = accept(&server_events, &client_events);
+            transport.shutdown().unwrap();
+            assert!(matches!(
+                server_events.recv_timeout(TIMEOUT),
+                Ok(ServerEvent::Connection(suspended, NetworkEvent::Suspended)) if suspended == peer
+            ));
+            (client, peer)
+        };
+
+        let (_client, peer) = suspend();
+        server.kick(peer.id, "bye").unwrap();
+        assert!(matches!(
+            server_events.recv_timeout(TIMEOUT),
+            Ok(ServerEvent::Connection(
+                disconnected,
+                NetworkEvent::Disconnect(DisconnectReason::Kicked(reason))
+            )) if disconnected == peer && reason == "bye"
+        ));
+
+        let (_client, peer) = suspend();
+        server.ban(peer.addr.ip());
+        assert!(matches!(
+            server_events.recv_timeout(TIMEOUT),
+            Ok(ServerEvent::Connection(
+                disconnected,
+                NetworkEvent::Disconnect(DisconnectReason::Kicked(reason))
+            )) if disconnected == peer && reason == "banned"
+        ));
+        assert!(server.connections().is_empty());
+    }
+
+    #[test]
+    fn test_kick_and_ban() {
+        let (server, server_events) = start_server(ServerConfig::default());
+        let (_client, client_events) = start_client(&server, ClientConfig::default());
+        let (peer, _) = accept(&server_events, &client_events);
+        server.kick(peer.id, "bye").unwrap();
+        let kicked = DisconnectReason::Kicked("bye".to_string());
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::Connection(NetworkEvent::Disconnect(reason))) if reason == kicked
+        ));
+        assert!(matches!(
+            server_events.recv_timeout(TIMEOUT),
+            Ok(ServerEvent::Connection(disconnected, NetworkEvent::Disconnect(reason)))
+                if disconnected == peer && reason == kicked
+        ));
+        assert!(
+            client_events
+                .recv_timeout(Duration::from_millis(300))
+                .is_err()
+        );
+        assert!(server.kick(peer.id, "again").is_err());
+
+        let (_client, client_events) = start_client(&server, ClientConfig::default());
+        let (peer, _) = accept(&server_events, &client_events);
+        server.ban(peer.addr.ip());
+        let banned = DisconnectReason::Kicked("banned".to_string());
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::Connection(NetworkEvent::Disconnect(reason))) if reason == banned
+        ));
+        assert!(matches!(
+            server_events.recv_timeout(TIMEOUT),
+            Ok(ServerEvent::Connection(_, NetworkEvent::Disconnect(reason))) if reason == banned
+        ));
+
+        let (_client, client_events) = start_client(&server, ClientConfig::default());
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::Connection(NetworkEvent::Rejected(
+                RejectReason::Banned
+            )))
+        ));
+        assert!(matches!(
+            server_events.recv_timeout(TIMEOUT),
+            Ok(ServerEvent::Connection(
+                _,
+                NetworkEvent::Rejected(RejectReason::Banned)
+            ))
+        ));
+        assert!(server.connections().is_empty());
+    }
+
+    #[test]
+    fn test_ephemeral_port() {
+        let (server, server_events) = start_server(ServerConfig::default());
+        let [local_addr] = server.local_addrs() else {
+            panic!("expected one listener, got {:?}", server.local_addrs());
+        };
+        assert_eq!(local_addr.ip(), Ipv4Addr::LOCALHOST);
+        assert_ne!(local_addr.port(), 0);
+
+        let (_client, client_events) = start_client(&server, ClientConfig::default());
+        let (peer, transport) = accept(&server_events, &client_events);
+        assert_eq!(transport.peer_addr().unwrap(), *local_addr);
+        assert_eq!(server.connections(), vec![peer]);
+    }
+
+    #[test]
+    fn test_codec_mismatch() {
+        let (server, server_events) = start_server(ServerConfig {
+            codec: CodecConfig {
+                encoding: Arc::new(Json),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let (_client, client_events) = start_client(
+            &server,
+            ClientConfig {
+                codec: CodecConfig {
+                    encoding: Arc::new(MessagePack),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::Connection(NetworkEvent::Rejected(
+                RejectReason::CodecMismatch { server, client }
+            ))) if server == "json" && client == "msgpack"
+        ));
+        assert!(matches!(
+            server_events.recv_timeout(TIMEOUT),
+            Ok(ServerEvent::Connection(
+                _,
+                NetworkEvent::Rejected(RejectReason::CodecMismatch { .. })
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_tls() {
+        let (tls, certificate) = TlsServer::self_signed(vec!["localhost".to_string()]).unwrap();
+        let (server, server_events) = start_server(ServerConfig {
+            tls: Some(tls),
+            ..Default::default()
+        });
+        let (_client, client_events) = start_client(
+            &server,
+            ClientConfig {
+                tls: Some(TlsClient::pinned(certificate)),
+                ..Default::default()
+            },
+        );
+        let (peer, transport) = accept(&server_events, &client_events);
+        assert!(transport.0.is_encrypted());
+
+        let large: String = (0..64 * 1024)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        for message in ["hello".to_string(), large] {
+            transport.send::<Message>(message.clone()).unwrap();
+            assert!(matches!(
+                server_events.recv_timeout(TIMEOUT),
+                Ok(ServerEvent::Connection(sender, NetworkEvent::Message(received)))
+                    if sender == peer && received == message
+            ));
+        }
+        server.send_to::<Message>(peer.id, 7).unwrap();
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::Connection(NetworkEvent::Message(7)))
+        ));
+    }
+
+    #[test]
+    fn test_bind_in_use() {
+        let (server, _server_events) = start_server(ServerConfig::default());
+        let (event_sender, _events) = channel::<ServerNetworkEvent<Message>>();
+        let Err(err) = MessageServer::bind::<Message>(event_sender, server.local_addrs()[0]) else {
+            panic!("bound a port that is already in use");
+        };
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn test_groups() {
+        let (server, server_events) = start_server(ServerConfig::default());
+        let (_a, a_events) = start_client(&server, ClientConfig::default());
+        let (a, _) = accept(&server_events, &a_events);
+        let (_b, b_events) = start_client(&server, ClientConfig::default());
+        let (b, _) = accept(&server_events, &b_events);
+        assert_ne!(a.id, b.id);
+
+        server.join_group(a.id, "red").unwrap();
+        assert_eq!(server.group_members("red"), vec![a.id]);
+        server.send_to_group::<Message>("red", 1);
+        server.broadcast_except::<Message>(a.id, 2);
+        server.broadcast::<Message>(3);
+        let received = |events: &Receiver<ClientNetworkEvent<Message>>| {
+            (0..2)
+                .map(|_| match events.recv_timeout(TIMEOUT) {
+                    Ok(ClientEvent::Connection(NetworkEvent::Message(message))) => message,
+                    event => panic!("expected a message, got {event:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(received(&a_events), [1, 3]);
+        assert_eq!(received(&b_events), [2, 3]);
+
+        server.leave_group(a.id, "red");
+        assert!(server.groups().is_empty());
+    }
+}
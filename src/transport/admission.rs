@@ -0,0 +1,79 @@
+use std::{collections::BTreeSet, net::IpAddr, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use super::Peer;
+
+#[derive(Debug, Clone)]
+pub struct Admission {
+    pub peer: Peer,
+    pub protocol_id: String,
+    pub protocol_version: u32,
+    pub resuming: bool,
+}
+
+type Hook = dyn Fn(&Admission) -> Result<(), String> + Send + Sync;
+
+#[derive(Clone)]
+pub struct AdmissionHook(Arc<Hook>);
+
+impl AdmissionHook {
+    pub fn new(hook: impl Fn(&Admission) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        AdmissionHook(Arc::new(hook))
+    }
+
+    pub(super) fn check(&self, admission: &Admission) -> Result<(), String> {
+        (self.0)(admission)
+    }
+}
+
+impl std::fmt::Debug for AdmissionHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdmissionHook").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BanList(BTreeSet<IpAddr>);
+
+impl BanList {
+    pub fn ban(&mut self, ip: IpAddr) -> bool {
+        self.0.insert(ip.to_canonical())
+    }
+
+    pub fn unban(&mut self, ip: IpAddr) -> bool {
+        self.0.remove(&ip.to_canonical())
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.0.contains(&ip.to_canonical())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::BanList;
+
+    #[test]
+    fn test_ban_list() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7));
+        let mapped = IpAddr::V6(Ipv4Addr::new(10, 0, 0, 7).to_ipv6_mapped());
+        let mut bans = BanList::default();
+        assert!(bans.ban(mapped));
+        assert!(!bans.ban(ip));
+        assert!(bans.is_banned(ip));
+        assert!(!bans.is_banned(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+
+        let json = serde_json::to_string(&bans).unwrap();
+        assert_eq!(serde_json::from_str::<BanList>(&json).unwrap(), bans);
+
+        assert!(bans.unban(ip));
+        assert!(!bans.is_banned(mapped));
+    }
+}
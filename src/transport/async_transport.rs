@@ -0,0 +1,933 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::{ControlFlow, Deref, DerefMut},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+        mpsc::Sender,
+    },
+    time::Duration,
+};
+
+use log::{debug, error};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{
+        TcpListener, TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    runtime::{Handle, Runtime},
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    task::{JoinHandle, JoinSet},
+    time::{interval, sleep, timeout},
+};
+
+use super::{
+    ACCEPT_POLL_INTERVAL, ClientEvent, ClientServerMessage, ConnectionId, DisconnectReason, Frame,
+    HANDSHAKE_TIMEOUT, Incoming, NetworkEvent, Peer, RejectReason, RequestId, ServerEvent,
+    client_message,
+    codec::CodecConfig,
+    handshake::{Hello, Verdict, Welcome},
+    heartbeat::{Heartbeat, HeartbeatConfig},
+    reconnect::ReconnectPolicy,
+    server_message,
+};
+
+pub type AsyncServerNetworkEvent<M> =
+    ServerEvent<AsyncServersideTransport, <M as ClientServerMessage>::ClientMessage>;
+pub type AsyncClientNetworkEvent<M> =
+    ClientEvent<AsyncClientsideTransport, <M as ClientServerMessage>::ServerMessage>;
+
+#[derive(Debug, Clone, Default)]
+pub struct AsyncConfig {
+    pub heartbeat: HeartbeatConfig,
+    pub reconnect: ReconnectPolicy,
+    pub codec: CodecConfig,
+}
+
+fn channel_closed<T>(_: T) -> io::Error {
+    io::Error::new(ErrorKind::ConnectionAborted, "Channel closed")
+}
+
+fn runtime() -> Result<Handle, io::Error> {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    if let Ok(handle) = Handle::try_current() {
+        return Ok(handle);
+    }
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime.handle().clone());
+    }
+    let runtime = Runtime::new()?;
+    Ok(RUNTIME.get_or_init(|| runtime).handle().clone())
+}
+
+async fn write_frame<M>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    codec: &CodecConfig,
+    message: &M,
+) -> Result<(), io::Error>
+where
+    M: Serialize,
+{
+    writer.write_all(&codec.encode_frame(message, None)?).await
+}
+
+async fn read_frame<M>(
+    reader: &mut (impl AsyncRead + Unpin),
+    codec: &CodecConfig,
+) -> Result<M, io::Error>
+where
+    M: DeserializeOwned,
+{
+    let mut len_buf = [0u8; 8];
+    reader.read_exact(&mut len_buf).await?;
+    let (len, compressed) = codec.frame_header(len_buf)?;
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf).await?;
+    codec.decode_frame(&buf, compressed, None)
+}
+
+async fn read_incoming<M>(
+    reader: &mut (impl AsyncRead + Unpin),
+    transport: &AsyncMessageTransporter,
+    heartbeat_timeout: Duration,
+) -> Result<Incoming<M>, io::Error>
+where
+    M: DeserializeOwned,
+{
+    loop {
+        let frame = timeout(
+            heartbeat_timeout,
+            read_frame::<Frame<M>>(reader, &transport.codec),
+        )
+        .await
+        .map_err(|_| io::Error::new(ErrorKind::TimedOut, "Heartbeat timed out"))??;
+        match frame {
+            Frame::Message(message) => return Ok(Incoming::Message(message)),
+            Frame::Request(id, message) => return Ok(Incoming::Request(id, message)),
+            Frame::Response(id, message) => return Ok(Incoming::Response(id, message)),
+            Frame::Ping(timestamp) => transport.send_frame(&Frame::<()>::Pong(timestamp))?,
+            Frame::Pong(timestamp) => transport.heartbeat.pong(timestamp),
+            Frame::Goodbye(reason) => return Ok(Incoming::Goodbye(reason)),
+            Frame::Snapshot(_) | Frame::SnapshotAck(_) => debug!("ignoring replication frame"),
+        }
+    }
+}
+
+async fn server_handshake<M>(
+    stream: &mut TcpStream,
+    codec: &CodecConfig,
+    peer: Peer,
+) -> Result<Result<(Hello, u32), RejectReason>, io::Error>
+where
+    M: ClientServerMessage,
+{
+    let server_hello = Hello::new::<M>(peer.addr, &*codec.encoding);
+    let codec = &CodecConfig::default();
+    write_frame(stream, codec, &server_hello).await?;
+    let verdict = match read_frame::<Hello>(stream, codec).await {
+        Ok(client_hello) => Hello::negotiate(&server_hello, &client_hello)
+            .map(|protocol_version| (client_hello, protocol_version)),
+        Err(err) if err.kind() == ErrorKind::InvalidData => Err(RejectReason::InvalidHandshake),
+        Err(err) => return Err(err),
+    };
+    let response: Verdict = match &verdict {
+        Ok((_, protocol_version)) => Ok(Welcome {
+            protocol_version: *protocol_version,
+            connection_id: peer.id,
+            udp_token: None,
+            session_token: None,
+            resumed: false,
+            compression: None,
+        }),
+        Err(reason) => Err(reason.clone()),
+    };
+    write_frame(stream, codec, &response).await?;
+    Ok(verdict)
+}
+
+async fn client_handshake<M>(
+    stream: &mut TcpStream,
+    codec: &CodecConfig,
+    socket: SocketAddr,
+) -> Result<Result<(Hello, Welcome), RejectReason>, io::Error>
+where
+    M: ClientServerMessage,
+{
+    let hello = Hello::new::<M>(socket, &*codec.encoding);
+    let codec = &CodecConfig::default();
+    let server_hello = match read_frame::<Hello>(stream, codec).await {
+        Ok(server_hello) if server_hello.is_valid() => server_hello,
+        Ok(_) => return Ok(Err(RejectReason::InvalidHandshake)),
+        Err(err) if err.kind() == ErrorKind::InvalidData => {
+            return Ok(Err(RejectReason::InvalidHandshake));
+        }
+        Err(err) => return Err(err),
+    };
+    write_frame(stream, codec, &hello).await?;
+    let verdict = match read_frame::<Verdict>(stream, codec).await {
+        Ok(verdict) => verdict,
+        Err(err) if err.kind() == ErrorKind::InvalidData => Err(RejectReason::InvalidHandshake),
+        Err(err) => return Err(err),
+    };
+    Ok(verdict.map(|welcome| (server_hello, welcome)))
+}
+
+async fn with_handshake_timeout<T>(
+    handshake: impl Future<Output = Result<T, io::Error>>,
+) -> Result<T, io::Error> {
+    timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| io::Error::new(ErrorKind::TimedOut, "Handshake timed out"))?
+}
+
+#[derive(Debug)]
+enum Outgoing {
+    Frame(Vec<u8>),
+    Shutdown,
+}
+
+#[derive(Debug, Clone)]
+pub struct AsyncMessageTransporter {
+    outgoing: UnboundedSender<Outgoing>,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    heartbeat: Arc<Heartbeat>,
+    codec: CodecConfig,
+    protocol_version: u32,
+    connection_id: ConnectionId,
+}
+
+impl AsyncMessageTransporter {
+    fn new(
+        stream: TcpStream,
+        protocol_version: u32,
+        connection_id: ConnectionId,
+        config: &AsyncConfig,
+    ) -> Result<(Self, OwnedReadHalf), io::Error> {
+        let peer_addr = stream.peer_addr()?;
+        let local_addr = stream.local_addr()?;
+        let (read_half, write_half) = stream.into_split();
+        let (outgoing, outgoing_receiver) = unbounded_channel();
+        tokio::spawn(Self::writer_task(write_half, outgoing_receiver));
+        let transporter = AsyncMessageTransporter {
+            outgoing,
+            peer_addr,
+            local_addr,
+            heartbeat: Arc::new(Heartbeat::new()),
+            codec: config.codec.clone(),
+            protocol_version,
+            connection_id,
+        };
+        tokio::spawn(
+            transporter
+                .clone()
+                .heartbeat_task(config.heartbeat.interval),
+        );
+        Ok((transporter, read_half))
+    }
+
+    async fn heartbeat_task(self, heartbeat_interval: Duration) {
+        let mut ticks = interval(heartbeat_interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            if self.send_frame(&self.heartbeat.ping::<()>()).is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn writer_task(
+        mut write_half: OwnedWriteHalf,
+        mut outgoing_receiver: UnboundedReceiver<Outgoing>,
+    ) {
+        while let Some(Outgoing::Frame(frame)) = outgoing_receiver.recv().await {
+            if let Err(err) = write_half.write_all(&frame).await {
+                debug!("failed to write to {:?}: {err}", write_half.peer_addr());
+                break;
+            }
+        }
+        let _ = write_half.shutdown().await;
+    }
+
+    fn send_frame<M>(&self, message: &M) -> Result<(), io::Error>
+    where
+        M: Serialize,
+    {
+        self.outgoing
+            .send(Outgoing::Frame(self.codec.encode_frame(message, None)?))
+            .map_err(channel_closed)
+    }
+
+    fn send<M>(&self, message: &M) -> Result<(), io::Error>
+    where
+        M: Serialize,
+    {
+        self.send_frame(&Frame::Message(message))
+    }
+
+    fn goodbye(&self, reason: DisconnectReason) {
+        let _ = self.send_frame(&Frame::<()>::Goodbye(reason));
+        let _ = self.shutdown();
+    }
+
+    pub fn shutdown(&self) -> Result<(), io::Error> {
+        self.outgoing
+            .send(Outgoing::Shutdown)
+            .map_err(channel_closed)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.heartbeat.rtt()
+    }
+}
+
+struct ShutdownOnDrop(AsyncMessageTransporter);
+
+impl Drop for ShutdownOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AsyncClientsideTransport(AsyncMessageTransporter);
+
+impl AsyncClientsideTransport {
+    pub fn send<M>(&self, message: M::ClientMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        self.0.send(&M::from(message))
+    }
+
+    pub fn blind_send<M>(&self, message: M::ClientMessage)
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        let _ = self.send::<M>(message);
+    }
+}
+
+impl Deref for AsyncClientsideTransport {
+    type Target = AsyncMessageTransporter;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for AsyncClientsideTransport {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AsyncServersideTransport(AsyncMessageTransporter);
+
+impl AsyncServersideTransport {
+    pub fn send<M>(&self, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.0.send(&M::from(message))
+    }
+
+    pub fn respond<M>(&self, id: RequestId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.0.send_frame(&Frame::Response(id, &M::from(message)))
+    }
+
+    pub fn blind_send<M>(&self, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let _ = self.send::<M>(message);
+    }
+}
+
+impl Deref for AsyncServersideTransport {
+    type Target = AsyncMessageTransporter;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for AsyncServersideTransport {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+type AsyncConnections = Arc<Mutex<HashMap<ConnectionId, AsyncServersideTransport>>>;
+
+pub struct AsyncMessageServer {
+    listener_tasks: Vec<JoinHandle<()>>,
+    connections: AsyncConnections,
+    local_addrs: Vec<SocketAddr>,
+    disconnect: Box<dyn Fn(Peer, DisconnectReason) + Send + Sync>,
+}
+
+impl AsyncMessageServer {
+    pub fn start<M>(
+        event_sender: Sender<impl From<AsyncServerNetworkEvent<M>> + Send + 'static>,
+        port: u16,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned + 'static,
+        M::ClientMessage: TryFrom<M>,
+    {
+        Self::start_with_config::<M>(event_sender, port, AsyncConfig::default())
+    }
+
+    pub fn start_with_config<M>(
+        event_sender: Sender<impl From<AsyncServerNetworkEvent<M>> + Send + 'static>,
+        port: u16,
+        config: AsyncConfig,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned + 'static,
+        M::ClientMessage: TryFrom<M>,
+    {
+        let runtime = runtime()?;
+        let mut listeners: Vec<(SocketAddr, TcpListener)> = Vec::new();
+        let mut last_err = None;
+        let _guard = runtime.enter();
+        for ip in [
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        ] {
+            let port = match listeners.first() {
+                Some((socket, _)) if port == 0 => socket.port(),
+                _ => port,
+            };
+            let socket = SocketAddr::new(ip, port);
+            let listener = std::net::TcpListener::bind(socket).and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            });
+            match listener {
+                Ok(listener) => {
+                    let socket = listener.local_addr()?;
+                    debug!("async message server starting on socket {socket}");
+                    listeners.push((socket, listener));
+                }
+                Err(err) => {
+                    debug!("unable to bind async message server to {socket}: {err}");
+                    last_err = Some(err);
+                }
+            }
+        }
+        if listeners.is_empty() {
+            return Err(last_err.unwrap());
+        }
+        let connections = AsyncConnections::default();
+        let next_id = Arc::new(AtomicU64::new(1));
+        let local_addrs = listeners.iter().map(|(socket, _)| *socket).collect();
+        let disconnect = {
+            let event_sender = event_sender.clone();
+            move |peer, reason| {
+                let event: AsyncServerNetworkEvent<M> =
+                    ServerEvent::Connection(peer, NetworkEvent::Disconnect(reason));
+                let _ = event_sender.send(event.into());
+            }
+        };
+        let listener_tasks = (listeners.into_iter())
+            .map(|(socket, listener)| {
+                runtime.spawn(Self::listener_task::<M>(
+                    event_sender.clone(),
+                    socket,
+                    listener,
+                    connections.clone(),
+                    next_id.clone(),
+                    config.clone(),
+                ))
+            })
+            .collect();
+        Ok(AsyncMessageServer {
+            listener_tasks,
+            connections,
+            local_addrs,
+            disconnect: Box::new(disconnect),
+        })
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn connections(&self) -> Vec<Peer> {
+        let mut peers: Vec<_> = (self.connections.lock().unwrap().iter())
+            .map(|(&id, transport)| Peer {
+                id,
+                addr: transport.peer_addr(),
+            })
+            .collect();
+        peers.sort_by_key(|peer| peer.id);
+        peers
+    }
+
+    pub fn connection(&self, id: ConnectionId) -> Option<AsyncServersideTransport> {
+        self.connections.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn send_to<M>(&self, id: ConnectionId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let Some(transport) = self.connection(id) else {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("No connection with id {id}"),
+            ));
+        };
+        transport.send::<M>(message)
+    }
+
+    async fn listener_task<M>(
+        event_sender: Sender<impl From<AsyncServerNetworkEvent<M>> + Send + 'static>,
+        socket: SocketAddr,
+        listener: TcpListener,
+        connections: AsyncConnections,
+        next_id: Arc<AtomicU64>,
+        config: AsyncConfig,
+    ) where
+        M: ClientServerMessage + DeserializeOwned + 'static,
+        M::ClientMessage: TryFrom<M>,
+    {
+        let mut connection_tasks = JoinSet::new();
+        loop {
+            while connection_tasks.try_join_next().is_some() {}
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    debug!("new client connected: {addr}");
+                    let peer = Peer {
+                        id: ConnectionId(next_id.fetch_add(1, Ordering::Relaxed)),
+                        addr,
+                    };
+                    connection_tasks.spawn(Self::connection_task::<M>(
+                        event_sender.clone(),
+                        stream,
+                        peer,
+                        connections.clone(),
+                        config.clone(),
+                    ));
+                }
+                Err(e) => {
+                    error!("Async message server listener error on {socket}: {e}");
+                    if (event_sender.send(ServerEvent::ListenerError(e).into())).is_err() {
+                        return;
+                    }
+                    sleep(ACCEPT_POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn connection_task<M>(
+        event_sender: Sender<impl From<AsyncServerNetworkEvent<M>> + Send + 'static>,
+        mut stream: TcpStream,
+        peer: Peer,
+        connections: AsyncConnections,
+        config: AsyncConfig,
+    ) where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ClientMessage: TryFrom<M>,
+    {
+        let send_event = |event: NetworkEvent<AsyncServersideTransport, M::ClientMessage>| {
+            (event_sender.send(ServerEvent::Connection(peer, event).into())).map_err(channel_closed)
+        };
+        let handshake =
+            with_handshake_timeout(server_handshake::<M>(&mut stream, &config.codec, peer)).await;
+        let (client_hello, protocol_version) = match handshake {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(reason)) => {
+                debug!("rejected client {peer}: {reason}");
+                let _ = send_event(NetworkEvent::Rejected(reason));
+                return;
+            }
+            Err(err) => {
+                error!("Handshake with client {peer} failed: {err}");
+                return;
+            }
+        };
+        let (transport, mut reader) =
+            match AsyncMessageTransporter::new(stream, protocol_version, peer.id, &config) {
+                Ok(connection) => connection,
+                Err(err) => {
+                    error!("Unable to set up connection with client {peer}: {err}");
+                    return;
+                }
+            };
+        let _shutdown = ShutdownOnDrop(transport.clone());
+        let session: Result<DisconnectReason, io::Error> = async {
+            connections
+                .lock()
+                .unwrap()
+                .insert(peer.id, AsyncServersideTransport(transport.clone()));
+            send_event(NetworkEvent::Connect {
+                transport: AsyncServersideTransport(transport.clone()),
+                my_socket_addr: client_hello.socket,
+            })?;
+            let reason = loop {
+                let incoming =
+                    read_incoming::<M>(&mut reader, &transport, config.heartbeat.timeout).await?;
+                let event = match incoming {
+                    Incoming::Message(message) => NetworkEvent::Message(client_message(message)?),
+                    Incoming::Request(id, message) => NetworkEvent::Request {
+                        id,
+                        message: client_message(message)?,
+                    },
+                    Incoming::Response(id, _) => {
+                        debug!("ignoring response to request {id} from client {peer}");
+                        continue;
+                    }
+                    Incoming::State(_) => continue,
+                    Incoming::Goodbye(reason) => break reason,
+                };
+                send_event(event)?;
+            };
+            Ok(reason)
+        }
+        .await;
+        let reason = session.unwrap_or_else(|err| DisconnectReason::from_error(&err));
+        if connections.lock().unwrap().remove(&peer.id).is_none() {
+            // the server was dropped and already reported this connection as closed
+            return;
+        }
+        debug!("connection ended with {peer}: {reason}");
+        let _ = send_event(NetworkEvent::Disconnect(reason));
+    }
+}
+
+impl Drop for AsyncMessageServer {
+    fn drop(&mut self) {
+        for (id, transport) in self.connections.lock().unwrap().drain() {
+            transport.goodbye(DisconnectReason::ServerClosing);
+            let peer = Peer {
+                id,
+                addr: transport.peer_addr(),
+            };
+            (self.disconnect)(peer, DisconnectReason::ServerClosing);
+        }
+        for listener_task in &self.listener_tasks {
+            listener_task.abort();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AsyncClientConnection {
+    transport: Option<AsyncMessageTransporter>,
+    closed: bool,
+}
+
+type SharedAsyncClientConnection = Arc<Mutex<AsyncClientConnection>>;
+
+pub struct AsyncMessageClient {
+    connection_task: JoinHandle<()>,
+    connection: SharedAsyncClientConnection,
+    disconnect: Box<dyn Fn(DisconnectReason) + Send + Sync>,
+}
+
+impl AsyncMessageClient {
+    pub fn start<M>(
+        event_sender: Sender<impl From<AsyncClientNetworkEvent<M>> + Send + 'static>,
+        socket: SocketAddr,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned + 'static,
+        M::ServerMessage: TryFrom<M>,
+    {
+        Self::start_with_config::<M>(event_sender, socket, AsyncConfig::default())
+    }
+
+    pub fn start_with_config<M>(
+        event_sender: Sender<impl From<AsyncClientNetworkEvent<M>> + Send + 'static>,
+        socket: SocketAddr,
+        config: AsyncConfig,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned + 'static,
+        M::ServerMessage: TryFrom<M>,
+    {
+        let connection = SharedAsyncClientConnection::default();
+        let disconnect = {
+            let event_sender = event_sender.clone();
+            move |reason| {
+                let event = NetworkEvent::Disconnect(reason);
+                let _ = event_sender.send(AsyncClientNetworkEvent::<M>::Connection(event).into());
+            }
+        };
+        let connection_task = runtime()?.spawn(Self::connection_task::<M>(
+            event_sender,
+            socket,
+            config,
+            connection.clone(),
+        ));
+        Ok(AsyncMessageClient {
+            connection_task,
+            connection,
+            disconnect: Box::new(disconnect),
+        })
+    }
+
+    async fn connection_task<M>(
+        event_sender: Sender<impl From<AsyncClientNetworkEvent<M>> + Send + 'static>,
+        socket: SocketAddr,
+        config: AsyncConfig,
+        shared_connection: SharedAsyncClientConnection,
+    ) where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ServerMessage: TryFrom<M>,
+    {
+        let send_event = |event: NetworkEvent<AsyncClientsideTransport, M::ServerMessage>| {
+            (event_sender.send(ClientEvent::Connection(event).into())).map_err(channel_closed)
+        };
+        let mut attempt = 0;
+        loop {
+            let connection: Result<ControlFlow<()>, io::Error> = async {
+                let mut stream = TcpStream::connect(socket).await?;
+                debug!("connected to {socket}");
+                let handshake = with_handshake_timeout(client_handshake::<M>(
+                    &mut stream,
+                    &config.codec,
+                    socket,
+                ))
+                .await;
+                let (server_hello, welcome) = match handshake {
+                    Ok(Ok(accepted)) => accepted,
+                    Ok(Err(reason)) => {
+                        error!("Rejected by server {socket}: {reason}");
+                        let _ = send_event(NetworkEvent::Rejected(reason));
+                        return Ok(ControlFlow::Break(()));
+                    }
+                    Err(err) => Err(io::Error::new(
+                        err.kind(),
+                        format!("Handshake failed: {err}"),
+                    ))?,
+                };
+                let (transport, mut reader) = AsyncMessageTransporter::new(
+                    stream,
+                    welcome.protocol_version,
+                    welcome.connection_id,
+                    &config,
+                )?;
+                attempt = 0;
+                let shutdown = ShutdownOnDrop(transport.clone());
+                {
+                    let mut shared_connection = shared_connection.lock().unwrap();
+                    if shared_connection.closed {
+                        return Ok(ControlFlow::Break(()));
+                    }
+                    shared_connection.transport = Some(transport.clone());
+                }
+                let session: Result<DisconnectReason, io::Error> = async {
+                    send_event(NetworkEvent::Connect {
+                        transport: AsyncClientsideTransport(transport.clone()),
+                        my_socket_addr: server_hello.socket,
+                    })?;
+                    let reason = loop {
+                        let incoming =
+                            read_incoming::<M>(&mut reader, &transport, config.heartbeat.timeout)
+                                .await?;
+                        let message = match incoming {
+                            Incoming::Message(message) => server_message(message)?,
+                            Incoming::Request(id, _) | Incoming::Response(id, _) => {
+                                debug!("ignoring rpc frame for request {id} from server {socket}");
+                                continue;
+                            }
+                            Incoming::State(_) => continue,
+                            Incoming::Goodbye(reason) => break reason,
+                        };
+                        send_event(NetworkEvent::Message(message))?;
+                    };
+                    Ok(reason)
+                }
+                .await;
+                let reason = session.unwrap_or_else(|err| DisconnectReason::from_error(&err));
+                drop(shutdown);
+                debug!("connection ended with {socket}: {reason}");
+                {
+                    let mut shared_connection = shared_connection.lock().unwrap();
+                    if shared_connection.closed
+                        || send_event(NetworkEvent::Disconnect(reason.clone())).is_err()
+                    {
+                        return Ok(ControlFlow::Break(()));
+                    }
+                    shared_connection.transport = None;
+                }
+                match reason {
+                    DisconnectReason::ConnectionLost(_) | DisconnectReason::ServerClosing => {
+                        Ok(ControlFlow::Continue(()))
+                    }
+                    _ => Ok(ControlFlow::Break(())),
+                }
+            }
+            .await;
+            match connection {
+                Ok(ControlFlow::Break(())) => return,
+                Ok(ControlFlow::Continue(())) => {}
+                Err(err) => debug!("unable to connect to {socket}: {err}"),
+            }
+            if shared_connection.lock().unwrap().closed {
+                return;
+            }
+            attempt += 1;
+            let Some(delay) = config.reconnect.delay(attempt) else {
+                debug!("giving up on connecting to {socket}");
+                let _ = event_sender.send(ClientEvent::GaveUp.into());
+                return;
+            };
+            debug!("reconnecting to {socket} in {delay:?} (attempt {attempt})");
+            let reconnecting = ClientEvent::Reconnecting { attempt, delay };
+            if event_sender.send(reconnecting.into()).is_err() {
+                return;
+            }
+            sleep(delay).await;
+        }
+    }
+}
+
+impl Drop for AsyncMessageClient {
+    fn drop(&mut self) {
+        let transport = {
+            let mut connection = self.connection.lock().unwrap();
+            connection.closed = true;
+            connection.transport.take()
+        };
+        if let Some(transport) = transport {
+            transport.goodbye(DisconnectReason::ClientClosing);
+            (self.disconnect)(DisconnectReason::ClientClosing);
+        }
+        self.connection_task.abort();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, sync::mpsc::channel, time::Duration};
+
+    use super::{
+        AsyncClientNetworkEvent, AsyncMessageClient, AsyncMessageServer, AsyncServerNetworkEvent,
+    };
+    use crate::transport::{
+        ClientEvent, DisconnectReason, NetworkEvent, ServerEvent, test::Message,
+    };
+
+    #[test]
+    fn test_async_roundtrip() {
+        let timeout = Duration::from_secs(5);
+        let (server_sender, server_events) = channel::<AsyncServerNetworkEvent<Message>>();
+        let server = AsyncMessageServer::start::<Message>(server_sender, 0).unwrap();
+        let port = server.local_addrs()[0].port();
+        let (client_sender, client_events) = channel::<AsyncClientNetworkEvent<Message>>();
+        let client = AsyncMessageClient::start::<Message>(
+            client_sender,
+            SocketAddr::from(([127, 0, 0, 1], port)),
+        )
+        .unwrap();
+
+        let Ok(ServerEvent::Connection(peer, NetworkEvent::Connect { transport, .. })) =
+            server_events.recv_timeout(timeout)
+        else {
+            panic!("server did not see the client connect");
+        };
+        let Ok(ClientEvent::Connection(NetworkEvent::Connect {
+            transport: client_transport,
+            ..
+        })) = client_events.recv_timeout(timeout)
+        else {
+            panic!("client did not connect");
+        };
+        assert_eq!(client_transport.connection_id(), peer.id);
+
+        client_transport
+            .send::<Message>("hello".to_string())
+            .unwrap();
+        assert!(matches!(
+            server_events.recv_timeout(timeout),
+            Ok(ServerEvent::Connection(_, NetworkEvent::Message(message))) if message == "hello"
+        ));
+        transport.send::<Message>(42).unwrap();
+        assert!(matches!(
+            client_events.recv_timeout(timeout),
+            Ok(ClientEvent::Connection(NetworkEvent::Message(42)))
+        ));
+
+        drop(client);
+        assert!(matches!(
+            client_events.recv_timeout(timeout),
+            Ok(ClientEvent::Connection(NetworkEvent::Disconnect(
+                DisconnectReason::ClientClosing
+            )))
+        ));
+        assert!(matches!(
+            server_events.recv_timeout(timeout),
+            Ok(ServerEvent::Connection(
+                disconnected,
+                NetworkEvent::Disconnect(DisconnectReason::ClientClosing)
+            )) if disconnected == peer
+        ));
+        assert!(server.connections().is_empty());
+
+        let (client_sender, client_events) = channel::<AsyncClientNetworkEvent<Message>>();
+        let _client = AsyncMessageClient::start::<Message>(
+            client_sender,
+            SocketAddr::from(([127, 0, 0, 1], port)),
+        )
+        .unwrap();
+        assert!(matches!(
+            client_events.recv_timeout(timeout),
+            Ok(ClientEvent::Connection(NetworkEvent::Connect { .. }))
+        ));
+        let Ok(ServerEvent::Connection(peer, NetworkEvent::Connect { .. })) =
+            server_events.recv_timeout(timeout)
+        else {
+            panic!("server did not see the client reconnect");
+        };
+        drop(server);
+        assert!(matches!(
+            server_events.recv_timeout(timeout),
+            Ok(ServerEvent::Connection(
+                disconnected,
+                NetworkEvent::Disconnect(DisconnectReason::ServerClosing)
+            )) if disconnected == peer
+        ));
+        assert!(server_events.recv_timeout(timeout).is_err());
+        assert!(matches!(
+            client_events.recv_timeout(timeout),
+            Ok(ClientEvent::Connection(NetworkEvent::Disconnect(
+                DisconnectReason::ServerClosing
+            )))
+        ));
+        assert!(matches!(
+            client_events.recv_timeout(timeout),
+            Ok(ClientEvent::Reconnecting { attempt: 1, .. })
+        ));
+    }
+}
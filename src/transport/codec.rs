@@ -0,0 +1,282 @@
+use std::{
+    fmt::Debug,
+    io::{self, ErrorKind},
+    sync::Arc,
+};
+
+use bincode::Options;
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::compression::Compressor;
+
+const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+const COMPRESSED_FLAG: u64 = 1 << 63;
+
+fn codec_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+pub trait Codec: Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, io::Error>;
+
+    fn deserialize(
+        &self,
+        buf: &[u8],
+        visit: &mut dyn FnMut(
+            &mut dyn erased_serde::Deserializer,
+        ) -> Result<(), erased_serde::Error>,
+    ) -> Result<(), io::Error>;
+}
+
+pub trait CodecExt: Codec {
+    fn encode<T>(&self, value: &T) -> Result<Vec<u8>, io::Error>
+    where
+        T: Serialize,
+    {
+        self.serialize(value)
+    }
+
+    fn decode<T>(&self, buf: &[u8]) -> Result<T, io::Error>
+    where
+        T: DeserializeOwned,
+    {
+        let mut value = None;
+        self.deserialize(buf, &mut |deserializer| {
+            value = Some(erased_serde::deserialize(deserializer)?);
+            Ok(())
+        })?;
+        value.ok_or_else(|| codec_error(format!("{} codec produced no value", self.name())))
+    }
+}
+
+impl<C: Codec + ?Sized> CodecExt for C {}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn name(&self) -> &str {
+        "bincode"
+    }
+
+    fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, io::Error> {
+        bincode::serialize(value).map_err(codec_error)
+    }
+
+    fn deserialize(
+        &self,
+        buf: &[u8],
+        visit: &mut dyn FnMut(
+            &mut dyn erased_serde::Deserializer,
+        ) -> Result<(), erased_serde::Error>,
+    ) -> Result<(), io::Error> {
+        // the same options as `bincode::deserialize`, so frames match `bincode::serialize`
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+        let mut deserializer = bincode::Deserializer::from_slice(buf, options);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(
+            &mut deserializer,
+        ))
+        .map_err(codec_error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, io::Error> {
+        serde_json::to_vec(value).map_err(codec_error)
+    }
+
+    fn deserialize(
+        &self,
+        buf: &[u8],
+        visit: &mut dyn FnMut(
+            &mut dyn erased_serde::Deserializer,
+        ) -> Result<(), erased_serde::Error>,
+    ) -> Result<(), io::Error> {
+        let mut deserializer = serde_json::Deserializer::from_slice(buf);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(
+            &mut deserializer,
+        ))
+        .map_err(codec_error)?;
+        deserializer.end().map_err(codec_error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn name(&self) -> &str {
+        "msgpack"
+    }
+
+    fn serialize(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, io::Error> {
+        rmp_serde::to_vec_named(value).map_err(codec_error)
+    }
+
+    fn deserialize(
+        &self,
+        buf: &[u8],
+        visit: &mut dyn FnMut(
+            &mut dyn erased_serde::Deserializer,
+        ) -> Result<(), erased_serde::Error>,
+    ) -> Result<(), io::Error> {
+        let mut deserializer = rmp_serde::Deserializer::from_read_ref(buf);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(
+            &mut deserializer,
+        ))
+        .map_err(codec_error)
+    }
+}
+
+pub(super) fn builtin_codec(name: &str) -> Option<Arc<dyn Codec>> {
+    match name {
+        "bincode" => Some(Arc::new(Bincode)),
+        "json" => Some(Arc::new(Json)),
+        "msgpack" => Some(Arc::new(MessagePack)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CodecConfig {
+    pub encoding: Arc<dyn Codec>,
+    pub max_frame_size: usize,
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        CodecConfig {
+            encoding: Arc::new(Bincode),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl CodecConfig {
+    fn check_len(&self, len: usize) -> Result<usize, io::Error> {
+        if len > self.max_frame_size {
+            return Err(io::Error::new(
+                ErrorKind::FileTooLarge,
+                format!("Message size cannot exceed {} bytes", self.max_frame_size),
+            ));
+        }
+        Ok(len)
+    }
+
+    pub(super) fn encode_frame<M>(
+        &self,
+        message: &M,
+        compressor: Option<&Compressor>,
+    ) -> Result<Vec<u8>, io::Error>
+    where
+        M: Serialize,
+    {
+        let encoded_message = self.encoding.encode(message)?;
+        self.check_len(encoded_message.len())?;
+        let (payload, compressed) = match compressor {
+            Some(compressor) => compressor.compress(encoded_message)?,
+            None => (encoded_message, false),
+        };
+        let mut header = payload.len() as u64;
+        if compressed {
+            header |= COMPRESSED_FLAG;
+        }
+        let mut frame = u64::to_le_bytes(header).to_vec();
+        frame.extend(payload);
+        Ok(frame)
+    }
+
+    pub(super) fn frame_header(&self, header_buf: [u8; 8]) -> Result<(usize, bool), io::Error> {
+        let header = u64::from_le_bytes(header_buf);
+        let len = self.check_len((header & !COMPRESSED_FLAG) as usize)?;
+        Ok((len, header & COMPRESSED_FLAG != 0))
+    }
+
+    pub(super) fn decode_frame<M>(
+        &self,
+        buf: &[u8],
+        compressed: bool,
+        compressor: Option<&Compressor>,
+    ) -> Result<M, io::Error>
+    where
+        M: DeserializeOwned,
+    {
+        if !compressed {
+            return self.encoding.decode(buf);
+        }
+        self.encoding.decode(&self.decompress(buf, compressor)?)
+    }
+
+    pub(super) fn decompress(
+        &self,
+        buf: &[u8],
+        compressor: Option<&Compressor>,
+    ) -> Result<Vec<u8>, io::Error> {
+        let Some(compressor) = compressor else {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Received a compressed frame without negotiated compression",
+            ));
+        };
+        compressor.decompress(buf, self.max_frame_size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::ErrorKind, sync::Arc};
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{Bincode, Codec, CodecConfig, Json, MessagePack};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Message {
+        Text(String),
+        Move { x: f32, y: f32 },
+        Quit,
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let messages = vec![
+            Message::Text("hello".to_string()),
+            Message::Move { x: 1.5, y: -2.0 },
+            Message::Quit,
+        ];
+        let codecs: [Arc<dyn Codec>; 3] =
+            [Arc::new(Bincode), Arc::new(Json), Arc::new(MessagePack)];
+        for encoding in codecs {
+            let codec = CodecConfig {
+                encoding,
+                ..Default::default()
+            };
+            let frame = codec.encode_frame(&messages, None).unwrap();
+            let (len, compressed) = codec.frame_header(frame[..8].try_into().unwrap()).unwrap();
+            assert_eq!(len, frame.len() - 8);
+            assert!(!compressed);
+            let decoded: Vec<Message> = codec.decode_frame(&frame[8..], false, None).unwrap();
+            assert_eq!(decoded, messages);
+        }
+
+        let codec = CodecConfig {
+            max_frame_size: 2,
+            ..Default::default()
+        };
+        let err = codec.encode_frame(&Message::Quit, None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::FileTooLarge);
+        let err = codec.frame_header(u64::to_le_bytes(5)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::FileTooLarge);
+    }
+}
@@ -0,0 +1,157 @@
+use std::io::{self, ErrorKind};
+
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+fn compression_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+fn too_large(max_size: usize) -> io::Error {
+    io::Error::new(
+        ErrorKind::FileTooLarge,
+        format!("Decompressed message size cannot exceed {max_size} bytes"),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    pub algorithms: Vec<Compression>,
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            algorithms: Vec::new(),
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub(super) fn negotiate(&self, offered: &[Compression]) -> Option<Compression> {
+        (self.algorithms.iter())
+            .find(|algorithm| offered.contains(algorithm))
+            .copied()
+    }
+
+    pub(super) fn compressor(&self, algorithm: Option<Compression>) -> Option<Compressor> {
+        algorithm.map(|algorithm| Compressor {
+            algorithm,
+            threshold: self.threshold,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Compressor {
+    algorithm: Compression,
+    threshold: usize,
+}
+
+impl Compressor {
+    pub(super) fn algorithm(&self) -> Compression {
+        self.algorithm
+    }
+
+    pub(super) fn compress(&self, buf: Vec<u8>) -> Result<(Vec<u8>, bool), io::Error> {
+        if buf.len() < self.threshold {
+            return Ok((buf, false));
+        }
+        let compressed = match self.algorithm {
+            Compression::Zstd => zstd::bulk::compress(&buf, zstd::DEFAULT_COMPRESSION_LEVEL)?,
+            Compression::Lz4 => lz4_flex::compress_prepend_size(&buf),
+        };
+        if compressed.len() >= buf.len() {
+            return Ok((buf, false));
+        }
+        Ok((compressed, true))
+    }
+
+    pub(super) fn decompress(&self, buf: &[u8], max_size: usize) -> Result<Vec<u8>, io::Error> {
+        match self.algorithm {
+            Compression::Zstd => {
+                let size = zstd::zstd_safe::get_frame_content_size(buf)
+                    .map_err(|_| compression_error("Invalid zstd frame"))?;
+                let capacity = match size {
+                    Some(size) if size > max_size as u64 => return Err(too_large(max_size)),
+                    Some(size) => size as usize,
+                    None => max_size,
+                };
+                zstd::bulk::decompress(buf, capacity)
+                    .map_err(|err| compression_error(format!("Invalid zstd frame: {err}")))
+            }
+            Compression::Lz4 => {
+                let Some((size_buf, buf)) = buf.split_first_chunk::<4>() else {
+                    return Err(compression_error("Invalid lz4 frame"));
+                };
+                let size = u32::from_le_bytes(*size_buf) as usize;
+                if size > max_size {
+                    return Err(too_large(max_size));
+                }
+                let mut decompressed = vec![0; size];
+                let len =
+                    lz4_flex::decompress_into(buf, &mut decompressed).map_err(compression_error)?;
+                decompressed.truncate(len);
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+
+    use super::{Compression, CompressionConfig};
+
+    #[test]
+    fn test_compression() {
+        let message: Vec<u8> = (0..64 * 1024).map(|i| (i / 64) as u8).collect();
+        for algorithm in [Compression::Zstd, Compression::Lz4] {
+            let config = CompressionConfig {
+                algorithms: vec![algorithm],
+                ..Default::default()
+            };
+            let compressor = config.compressor(Some(algorithm)).unwrap();
+
+            let (small, compressed) = compressor.compress(vec![0; 16]).unwrap();
+            assert!(!compressed);
+            assert_eq!(small, vec![0; 16]);
+
+            let (frame, compressed) = compressor.compress(message.clone()).unwrap();
+            assert!(compressed);
+            assert!(frame.len() < message.len());
+            assert_eq!(
+                compressor.decompress(&frame, message.len()).unwrap(),
+                message
+            );
+            let err = compressor
+                .decompress(&frame, message.len() - 1)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::FileTooLarge);
+        }
+
+        let server = CompressionConfig {
+            algorithms: vec![Compression::Zstd, Compression::Lz4],
+            ..Default::default()
+        };
+        assert_eq!(
+            server.negotiate(&[Compression::Lz4]),
+            Some(Compression::Lz4)
+        );
+        assert_eq!(
+            server.negotiate(&[Compression::Lz4, Compression::Zstd]),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(server.negotiate(&[]), None);
+    }
+}
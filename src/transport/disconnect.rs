@@ -0,0 +1,45 @@
+use std::{
+    fmt::Display,
+    io::{self, ErrorKind},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    ServerClosing,
+    ClientClosing,
+    Kicked(String),
+    ProtocolError(String),
+    ConnectionLost(String),
+    SessionExpired,
+}
+
+impl DisconnectReason {
+    pub(super) fn from_error(err: &io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::InvalidData | ErrorKind::FileTooLarge => {
+                DisconnectReason::ProtocolError(err.to_string())
+            }
+            _ => DisconnectReason::ConnectionLost(err.to_string()),
+        }
+    }
+
+    pub(super) fn is_protocol_error(&self) -> bool {
+        matches!(self, DisconnectReason::ProtocolError(_))
+    }
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::ServerClosing => write!(f, "server closing"),
+            DisconnectReason::ClientClosing => write!(f, "client closing"),
+            DisconnectReason::Kicked(reason) if reason.is_empty() => write!(f, "kicked"),
+            DisconnectReason::Kicked(reason) => write!(f, "kicked: {reason}"),
+            DisconnectReason::ProtocolError(err) => write!(f, "protocol error: {err}"),
+            DisconnectReason::ConnectionLost(err) => write!(f, "connection lost: {err}"),
+            DisconnectReason::SessionExpired => write!(f, "session expired"),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        Arc, Mutex,
+        mpsc::{RecvTimeoutError, Sender, channel},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::{
+    ClientServerMessage, MessageServer,
+    codec::{Bincode, CodecExt},
+    is_timeout,
+};
+
+pub const DEFAULT_DISCOVERY_PORT: u16 = 47777;
+const DISCOVERY_MAGIC: [u8; 4] = *b"GGND";
+const DISCOVERY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_ANNOUNCEMENT_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiscoveryConfig {
+    pub target: SocketAddr,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            target: SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), DEFAULT_DISCOVERY_PORT),
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Announcement {
+    magic: [u8; 4],
+    protocol_id: String,
+    protocol_version: u32,
+    name: String,
+    players: usize,
+    port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredServer {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub players: usize,
+    pub protocol_version: u32,
+    pub last_seen: Instant,
+}
+
+fn unspecified(target: SocketAddr, port: u16) -> SocketAddr {
+    match target {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+    }
+}
+
+// several games on one machine may browse at once, so the discovery port is shared
+fn bind_shared(addr: SocketAddr) -> Result<UdpSocket, io::Error> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+pub struct ServerAnnouncer {
+    thread: Option<JoinHandle<()>>,
+    thread_kill: Sender<()>,
+}
+
+impl ServerAnnouncer {
+    pub fn start<M>(
+        server: &MessageServer,
+        name: impl Into<String>,
+        config: DiscoveryConfig,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage,
+    {
+        let port = server.local_addrs[0].port();
+        let name = name.into();
+        let announcement = move |players| Announcement {
+            magic: DISCOVERY_MAGIC,
+            protocol_id: M::PROTOCOL_ID.to_string(),
+            protocol_version: M::PROTOCOL_VERSION,
+            name: name.clone(),
+            players,
+            port,
+        };
+        if Bincode.encode(&announcement(0))?.len() > MAX_ANNOUNCEMENT_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Server announcement cannot exceed {MAX_ANNOUNCEMENT_SIZE} bytes"),
+            ));
+        }
+        let socket = UdpSocket::bind(unspecified(config.target, 0))?;
+        if config.target.is_ipv4() {
+            socket.set_broadcast(true)?;
+        }
+        let connections = server.connections.clone();
+        let (thread_kill, deathswitch) = channel();
+        let thread = thread::spawn(move || {
+            loop {
+                let announcement = announcement(connections.lock().unwrap().ids().len());
+                let result: Result<_, io::Error> =
+                    try { socket.send_to(&Bincode.encode(&announcement)?, config.target)? };
+                if let Err(err) = result {
+                    debug!("unable to announce server to {}: {err}", config.target);
+                }
+                if deathswitch.recv_timeout(config.interval) != Err(RecvTimeoutError::Timeout) {
+                    break;
+                }
+            }
+        });
+        Ok(ServerAnnouncer {
+            thread: Some(thread),
+            thread_kill,
+        })
+    }
+}
+
+impl Drop for ServerAnnouncer {
+    fn drop(&mut self) {
+        let _ = self.thread_kill.send(());
+        self.thread.take().unwrap().join().unwrap();
+    }
+}
+
+type DiscoveredServers = Arc<Mutex<HashMap<SocketAddr, DiscoveredServer>>>;
+
+pub struct ServerBrowser {
+    thread: Option<JoinHandle<()>>,
+    thread_kill: Sender<()>,
+    servers: DiscoveredServers,
+    timeout: Duration,
+    local_addr: SocketAddr,
+}
+
+impl ServerBrowser {
+    pub fn start<M>(config: DiscoveryConfig) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage,
+    {
+        let socket = bind_shared(unspecified(config.target, config.target.port()))?;
+        match config.target.ip() {
+            IpAddr::V4(group) if group.is_multicast() => {
+                socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?
+            }
+            IpAddr::V6(group) if group.is_multicast() => socket.join_multicast_v6(&group, 0)?,
+            _ => {}
+        }
+        socket.set_read_timeout(Some(DISCOVERY_POLL_INTERVAL))?;
+        let local_addr = socket.local_addr()?;
+        let servers = DiscoveredServers::default();
+        let (thread_kill, deathswitch) = channel::<()>();
+        let thread = thread::spawn({
+            let servers = servers.clone();
+            move || {
+                let mut buf = [0; MAX_ANNOUNCEMENT_SIZE];
+                while deathswitch.try_recv().is_err() {
+                    let (len, sender) = match socket.recv_from(&mut buf) {
+                        Ok(received) => received,
+                        Err(err) if is_timeout(&err) => continue,
+                        Err(err) => {
+                            debug!("unable to receive server announcement: {err}");
+                            continue;
+                        }
+                    };
+                    let Ok(announcement) = Bincode.decode::<Announcement>(&buf[..len]) else {
+                        debug!("ignoring malformed announcement from {sender}");
+                        continue;
+                    };
+                    if announcement.magic != DISCOVERY_MAGIC
+                        || announcement.protocol_id != M::PROTOCOL_ID
+                    {
+                        continue;
+                    }
+                    let addr = SocketAddr::new(sender.ip().to_canonical(), announcement.port);
+                    servers.lock().unwrap().insert(
+                        addr,
+                        DiscoveredServer {
+                            name: announcement.name,
+                            addr,
+                            players: announcement.players,
+                            protocol_version: announcement.protocol_version,
+                            last_seen: Instant::now(),
+                        },
+                    );
+                }
+            }
+        });
+        Ok(ServerBrowser {
+            thread: Some(thread),
+            thread_kill,
+            servers,
+            timeout: config.timeout,
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn servers(&self) -> Vec<DiscoveredServer> {
+        let mut servers = self.servers.lock().unwrap();
+        servers.retain(|_, server| server.last_seen.elapsed() < self.timeout);
+        let mut servers: Vec<_> = servers.values().cloned().collect();
+        servers.sort_by(|a, b| a.name.cmp(&b.name).then(a.addr.cmp(&b.addr)));
+        servers
+    }
+}
+
+impl Drop for ServerBrowser {
+    fn drop(&mut self) {
+        let _ = self.thread_kill.send(());
+        self.thread.take().unwrap().join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::ErrorKind,
+        sync::mpsc::channel,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{DiscoveryConfig, MAX_ANNOUNCEMENT_SIZE, ServerAnnouncer, ServerBrowser};
+    use crate::transport::{ClientServerMessage, MessageServer, ServerNetworkEvent};
+
+    #[derive(serde::Deserialize)]
+    struct Message;
+
+    impl ClientServerMessage for Message {
+        type ClientMessage = ();
+        type ServerMessage = ();
+
+        const PROTOCOL_ID: &'static str = "discovery-test";
+    }
+
+    impl TryFrom<Message> for () {
+        type Error = ();
+
+        fn try_from(_: Message) -> Result<Self, Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn wait_for<T>(mut check: impl FnMut() -> Option<T>) -> T {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(value) = check() {
+                return value;
+            }
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_discovery() {
+        let browser = ServerBrowser::start::<Message>(DiscoveryConfig {
+            target: "127.0.0.1:0".parse().unwrap(),
+            timeout: Duration::from_millis(300),
+            ..Default::default()
+        })
+        .unwrap();
+        let (event_sender, _events) = channel::<ServerNetworkEvent<Message>>();
+        let server = MessageServer::bind::<Message>(event_sender, "127.0.0.1:0").unwrap();
+        let announcer = ServerAnnouncer::start::<Message>(
+            &server,
+            "test server",
+            DiscoveryConfig {
+                target: browser.local_addr(),
+                interval: Duration::from_millis(50),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let oversized = ServerAnnouncer::start::<Message>(
+            &server,
+            "x".repeat(MAX_ANNOUNCEMENT_SIZE),
+            DiscoveryConfig::default(),
+        );
+        assert_eq!(oversized.err().unwrap().kind(), ErrorKind::InvalidInput);
+
+        let discovered = wait_for(|| browser.servers().pop());
+        assert_eq!(discovered.name, "test server");
+        assert_eq!(discovered.addr, server.local_addrs()[0]);
+        assert_eq!(discovered.players, 0);
+
+        drop(announcer);
+        wait_for(|| browser.servers().is_empty().then_some(()));
+
+        let shared = ServerBrowser::start::<Message>(DiscoveryConfig {
+            target: browser.local_addr(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(shared.local_addr(), browser.local_addr());
+    }
+}
@@ -0,0 +1,198 @@
+use std::{io, time::Duration};
+
+use serde::Serialize;
+
+use super::{
+    ClientServerMessage, ClientsideTransport, ConnectionId, Delivery, MessageServer, Peer,
+    RequestId, RpcHandle, ServersideTransport,
+};
+
+pub trait ClientTransport {
+    fn connection_id(&self) -> ConnectionId;
+
+    fn send<M>(&self, message: M::ClientMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize;
+
+    fn send_with<M>(&self, message: M::ClientMessage, delivery: Delivery) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize;
+
+    fn request<M>(
+        &self,
+        message: M::ClientMessage,
+        timeout: Duration,
+    ) -> Result<RpcHandle<M>, io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize + 'static,
+        M::ServerMessage: TryFrom<M>;
+
+    fn blind_send<M>(&self, message: M::ClientMessage)
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        let _ = self.send::<M>(message);
+    }
+}
+
+pub trait ServerTransport {
+    fn connection_id(&self) -> ConnectionId;
+
+    fn send<M>(&self, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize;
+
+    fn send_with<M>(&self, message: M::ServerMessage, delivery: Delivery) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize;
+
+    fn respond<M>(&self, id: RequestId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize;
+
+    fn blind_send<M>(&self, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let _ = self.send::<M>(message);
+    }
+}
+
+pub trait MessageHost {
+    type Transport: ServerTransport;
+
+    fn connections(&self) -> Vec<Peer>;
+
+    fn connection(&self, id: ConnectionId) -> Option<Self::Transport>;
+
+    fn send_to<M>(&self, id: ConnectionId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize;
+
+    fn respond<M>(
+        &self,
+        id: ConnectionId,
+        request: RequestId,
+        message: M::ServerMessage,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize;
+
+    fn broadcast<M>(&self, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize;
+
+    fn broadcast_except<M>(&self, except: ConnectionId, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize;
+
+    fn kick(&self, id: ConnectionId, reason: impl Into<String>) -> Result<(), io::Error>;
+}
+
+impl ClientTransport for ClientsideTransport {
+    fn connection_id(&self) -> ConnectionId {
+        self.0.connection_id()
+    }
+
+    fn send<M>(&self, message: M::ClientMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        ClientsideTransport::send::<M>(self, message)
+    }
+
+    fn send_with<M>(&self, message: M::ClientMessage, delivery: Delivery) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        ClientsideTransport::send_with::<M>(self, message, delivery)
+    }
+
+    fn request<M>(
+        &self,
+        message: M::ClientMessage,
+        timeout: Duration,
+    ) -> Result<RpcHandle<M>, io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize + 'static,
+        M::ServerMessage: TryFrom<M>,
+    {
+        ClientsideTransport::request::<M>(self, message, timeout)
+    }
+}
+
+impl ServerTransport for ServersideTransport {
+    fn connection_id(&self) -> ConnectionId {
+        self.0.connection_id()
+    }
+
+    fn send<M>(&self, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        ServersideTransport::send::<M>(self, message)
+    }
+
+    fn send_with<M>(&self, message: M::ServerMessage, delivery: Delivery) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        ServersideTransport::send_with::<M>(self, message, delivery)
+    }
+
+    fn respond<M>(&self, id: RequestId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        ServersideTransport::respond::<M>(self, id, message)
+    }
+}
+
+impl MessageHost for MessageServer {
+    type Transport = ServersideTransport;
+
+    fn connections(&self) -> Vec<Peer> {
+        MessageServer::connections(self)
+    }
+
+    fn connection(&self, id: ConnectionId) -> Option<ServersideTransport> {
+        MessageServer::connection(self, id)
+    }
+
+    fn send_to<M>(&self, id: ConnectionId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        MessageServer::send_to::<M>(self, id, message)
+    }
+
+    fn respond<M>(
+        &self,
+        id: ConnectionId,
+        request: RequestId,
+        message: M::ServerMessage,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        MessageServer::respond::<M>(self, id, request, message)
+    }
+
+    fn broadcast<M>(&self, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        MessageServer::broadcast::<M>(self, message)
+    }
+
+    fn broadcast_except<M>(&self, except: ConnectionId, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        MessageServer::broadcast_except::<M>(self, except, message)
+    }
+
+    fn kick(&self, id: ConnectionId, reason: impl Into<String>) -> Result<(), io::Error> {
+        MessageServer::kick(self, id, reason)
+    }
+}
@@ -0,0 +1,226 @@
+use std::{fmt::Display, net::SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+use super::{ClientServerMessage, Codec, ConnectionId, Token, compression::Compression};
+
+const MAGIC: [u8; 4] = *b"GGNR";
+const TRANSPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReason {
+    InvalidHandshake,
+    TransportVersionMismatch {
+        server: u32,
+        client: u32,
+    },
+    CodecMismatch {
+        server: String,
+        client: String,
+    },
+    ProtocolMismatch {
+        server: String,
+        client: String,
+    },
+    VersionMismatch {
+        server: (u32, u32),
+        client: (u32, u32),
+    },
+    Banned,
+    ServerFull,
+    Refused(String),
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::InvalidHandshake => write!(f, "invalid handshake"),
+            RejectReason::TransportVersionMismatch { server, client } => write!(
+                f,
+                "transport version mismatch: server uses version {server}, client uses version {client}"
+            ),
+            RejectReason::CodecMismatch { server, client } => write!(
+                f,
+                "codec mismatch: server encodes with {server}, client encodes with {client}"
+            ),
+            RejectReason::ProtocolMismatch { server, client } => write!(
+                f,
+                "protocol mismatch: server speaks {server:?}, client speaks {client:?}"
+            ),
+            RejectReason::VersionMismatch {
+                server: (server_min, server_max),
+                client: (client_min, client_max),
+            } => write!(
+                f,
+                "protocol version mismatch: server supports versions {server_min}-{server_max}, client supports versions {client_min}-{client_max}"
+            ),
+            RejectReason::Banned => write!(f, "banned from server"),
+            RejectReason::ServerFull => write!(f, "server is full"),
+            RejectReason::Refused(reason) => write!(f, "refused: {reason}"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Hello {
+    magic: [u8; 4],
+    transport_version: u32,
+    // the handshake itself always uses the default codec, so a peer configured with a
+    // different one can still be told why it was rejected
+    codec: String,
+    protocol_id: String,
+    min_protocol_version: u32,
+    protocol_version: u32,
+    pub(super) socket: SocketAddr,
+    pub(super) resume: Option<SessionTicket>,
+    pub(super) compression: Vec<Compression>,
+}
+
+impl Hello {
+    pub(super) fn new<M>(socket: SocketAddr, codec: &dyn Codec) -> Hello
+    where
+        M: ClientServerMessage,
+    {
+        Hello {
+            magic: MAGIC,
+            transport_version: TRANSPORT_VERSION,
+            codec: codec.name().to_string(),
+            protocol_id: M::PROTOCOL_ID.to_string(),
+            min_protocol_version: M::MIN_PROTOCOL_VERSION,
+            protocol_version: M::PROTOCOL_VERSION,
+            socket,
+            resume: None,
+            compression: Vec::new(),
+        }
+    }
+
+    pub(super) fn protocol_id(&self) -> &str {
+        &self.protocol_id
+    }
+
+    pub(super) fn is_valid(&self) -> bool {
+        self.magic == MAGIC
+    }
+
+    pub(super) fn negotiate(server: &Hello, client: &Hello) -> Result<u32, RejectReason> {
+        if !server.is_valid() || !client.is_valid() {
+            return Err(RejectReason::InvalidHandshake);
+        }
+        if server.transport_version != client.transport_version {
+            return Err(RejectReason::TransportVersionMismatch {
+                server: server.transport_version,
+                client: client.transport_version,
+            });
+        }
+        if server.codec != client.codec {
+            return Err(RejectReason::CodecMismatch {
+                server: server.codec.clone(),
+                client: client.codec.clone(),
+            });
+        }
+        if server.protocol_id != client.protocol_id {
+            return Err(RejectReason::ProtocolMismatch {
+                server: server.protocol_id.clone(),
+                client: client.protocol_id.clone(),
+            });
+        }
+        let version = server.protocol_version.min(client.protocol_version);
+        if version < server.min_protocol_version.max(client.min_protocol_version) {
+            return Err(RejectReason::VersionMismatch {
+                server: (server.min_protocol_version, server.protocol_version),
+                client: (client.min_protocol_version, client.protocol_version),
+            });
+        }
+        Ok(version)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) struct SessionTicket {
+    pub(super) connection_id: ConnectionId,
+    pub(super) token: Token,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Welcome {
+    pub(super) protocol_version: u32,
+    pub(super) connection_id: ConnectionId,
+    pub(super) udp_token: Option<Token>,
+    pub(super) session_token: Option<Token>,
+    pub(super) resumed: bool,
+    pub(super) compression: Option<Compression>,
+}
+
+pub(super) type Verdict = Result<Welcome, RejectReason>;
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::{Hello, RejectReason};
+    use crate::transport::{Bincode, ClientServerMessage, Json};
+
+    struct V1;
+    struct V2;
+    struct V3;
+    struct Other;
+
+    impl ClientServerMessage for V1 {
+        type ClientMessage = ();
+        type ServerMessage = ();
+        const PROTOCOL_ID: &'static str = "game";
+        const PROTOCOL_VERSION: u32 = 1;
+    }
+
+    impl ClientServerMessage for V2 {
+        type ClientMessage = ();
+        type ServerMessage = ();
+        const PROTOCOL_ID: &'static str = "game";
+        const MIN_PROTOCOL_VERSION: u32 = 1;
+        const PROTOCOL_VERSION: u32 = 2;
+    }
+
+    impl ClientServerMessage for V3 {
+        type ClientMessage = ();
+        type ServerMessage = ();
+        const PROTOCOL_ID: &'static str = "game";
+        const PROTOCOL_VERSION: u32 = 3;
+    }
+
+    impl ClientServerMessage for Other {
+        type ClientMessage = ();
+        type ServerMessage = ();
+        const PROTOCOL_ID: &'static str = "other";
+        const PROTOCOL_VERSION: u32 = 1;
+    }
+
+    fn hello<M: ClientServerMessage>() -> Hello {
+        Hello::new::<M>(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0), &Bincode)
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Hello::negotiate(&hello::<V1>(), &hello::<V1>()), Ok(1));
+        assert_eq!(Hello::negotiate(&hello::<V2>(), &hello::<V1>()), Ok(1));
+        assert_eq!(Hello::negotiate(&hello::<V1>(), &hello::<V2>()), Ok(1));
+        assert_eq!(
+            Hello::negotiate(&hello::<V3>(), &hello::<V2>()),
+            Err(RejectReason::VersionMismatch {
+                server: (3, 3),
+                client: (1, 2)
+            })
+        );
+        assert!(matches!(
+            Hello::negotiate(&hello::<V1>(), &hello::<Other>()),
+            Err(RejectReason::ProtocolMismatch { .. })
+        ));
+        let json = Hello::new::<V1>(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0), &Json);
+        assert_eq!(
+            Hello::negotiate(&hello::<V1>(), &json),
+            Err(RejectReason::CodecMismatch {
+                server: "bincode".to_string(),
+                client: "json".to_string()
+            })
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    sync::{
+        Mutex,
+        mpsc::{RecvTimeoutError, Sender, channel},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use super::{Frame, MessageTransporter};
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Heartbeat {
+    epoch: Instant,
+    rtt: Mutex<Option<Duration>>,
+}
+
+impl Heartbeat {
+    pub(super) fn new() -> Self {
+        Heartbeat {
+            epoch: Instant::now(),
+            rtt: Mutex::new(None),
+        }
+    }
+
+    pub(super) fn ping<M>(&self) -> Frame<M> {
+        Frame::Ping(self.epoch.elapsed().as_micros() as u64)
+    }
+
+    pub(super) fn pong(&self, timestamp: u64) {
+        let rtt = (self.epoch.elapsed()).saturating_sub(Duration::from_micros(timestamp));
+        *self.rtt.lock().unwrap() = Some(rtt);
+    }
+
+    pub(super) fn rtt(&self) -> Option<Duration> {
+        *self.rtt.lock().unwrap()
+    }
+}
+
+pub(super) struct HeartbeatThread {
+    thread: Option<JoinHandle<()>>,
+    thread_kill: Sender<()>,
+}
+
+impl HeartbeatThread {
+    pub(super) fn spawn(transport: MessageTransporter, interval: Duration) -> Self {
+        let (thread_kill, deathswitch) = channel();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = deathswitch.recv_timeout(interval) {
+                let ping = transport.heartbeat.ping::<()>();
+                if let Err(err) = transport.send_frame(&ping) {
+                    debug!("unable to send heartbeat: {err}");
+                    break;
+                }
+            }
+        });
+        HeartbeatThread {
+            thread: Some(thread),
+            thread_kill,
+        }
+    }
+}
+
+impl Drop for HeartbeatThread {
+    fn drop(&mut self) {
+        let _ = self.thread_kill.send(());
+        self.thread.take().unwrap().join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::ErrorKind,
+        net::{Ipv4Addr, TcpListener, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::HeartbeatThread;
+    use crate::transport::{DisconnectReason, Incoming, MessageTransporter};
+
+    fn connected_pair() -> (MessageTransporter, MessageTransporter) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        (
+            MessageTransporter::new(accepted),
+            MessageTransporter::new(stream),
+        )
+    }
+
+    #[test]
+    fn test_heartbeat_timeout() {
+        let interval = Duration::from_millis(50);
+        let timeout = Duration::from_millis(400);
+
+        let (mut transport, _silent) = connected_pair();
+        transport.set_read_timeout(Some(timeout)).unwrap();
+        let _heartbeat = HeartbeatThread::spawn(transport.try_clone().unwrap(), interval);
+        let start = Instant::now();
+        let Err(err) = transport.recv_incoming::<()>() else {
+            panic!("a silent peer should not produce any frames");
+        };
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(matches!(
+            DisconnectReason::from_error(&err),
+            DisconnectReason::ConnectionLost(_)
+        ));
+        assert!(transport.rtt().is_none());
+
+        let (mut transport, peer) = connected_pair();
+        transport.set_read_timeout(Some(timeout)).unwrap();
+        let heartbeat = HeartbeatThread::spawn(transport.try_clone().unwrap(), interval);
+        let mut responder = peer.try_clone().unwrap();
+        let responder_thread = thread::spawn(move || responder.recv_incoming::<()>().is_err());
+        let peer_thread = thread::spawn(move || {
+            thread::sleep(timeout * 2);
+            peer.send(&()).unwrap();
+            peer
+        });
+        let start = Instant::now();
+        assert!(matches!(
+            transport.recv_incoming::<()>(),
+            Ok(Incoming::Message(()))
+        ));
+        assert!(start.elapsed() >= timeout * 2);
+        assert!(transport.rtt().is_some());
+        drop(heartbeat);
+        peer_thread.join().unwrap().shutdown().unwrap();
+        assert!(responder_thread.join().unwrap());
+    }
+}
@@ -0,0 +1,656 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Deref,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::Sender,
+    },
+    time::Duration,
+};
+
+use log::debug;
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::{
+    ClientEvent, ClientServerMessage, ClientTransport, ConnectionId, Delivery, DisconnectReason,
+    Frame, MessageHost, NetworkEvent, Peer, RequestId, RpcHandle, ServerEvent, ServerTransport,
+    client_message,
+    codec::{Bincode, CodecExt},
+    not_connected,
+    rpc::PendingRequests,
+    server_message,
+};
+
+const LOOPBACK_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+pub type LoopbackServerNetworkEvent<M> =
+    ServerEvent<LoopbackServersideTransport, <M as ClientServerMessage>::ClientMessage>;
+pub type LoopbackClientNetworkEvent<M> =
+    ClientEvent<LoopbackClientsideTransport, <M as ClientServerMessage>::ServerMessage>;
+
+fn channel_closed<T>(_: T) -> io::Error {
+    io::Error::new(ErrorKind::ConnectionAborted, "Channel closed")
+}
+
+enum Packet {
+    Connect(LoopbackTransporter),
+    Frame(Vec<u8>),
+    Close(DisconnectReason),
+}
+
+type Inbox = Arc<dyn Fn(Packet) -> Result<(), io::Error> + Send + Sync>;
+
+struct Link {
+    to_server: Inbox,
+    to_client: Inbox,
+    closed: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct LoopbackTransporter {
+    link: Arc<Link>,
+    serverside: bool,
+    requests: Arc<PendingRequests>,
+    connection_id: ConnectionId,
+}
+
+impl std::fmt::Debug for LoopbackTransporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoopbackTransporter")
+            .field("serverside", &self.serverside)
+            .field("connection_id", &self.connection_id)
+            .field("connected", &self.is_connected())
+            .finish()
+    }
+}
+
+impl LoopbackTransporter {
+    fn send_encoded(&self, frame: Vec<u8>) -> Result<(), io::Error> {
+        if !self.is_connected() {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "Loopback connection is closed",
+            ));
+        }
+        match self.serverside {
+            true => (self.link.to_client)(Packet::Frame(frame)),
+            false => (self.link.to_server)(Packet::Frame(frame)),
+        }
+    }
+
+    fn send_frame<M>(&self, frame: &Frame<M>) -> Result<(), io::Error>
+    where
+        M: Serialize,
+    {
+        self.send_encoded(Bincode.encode(frame)?)
+    }
+
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !self.link.closed.load(Ordering::Acquire)
+    }
+
+    pub fn shutdown(&self) {
+        self.close(match self.serverside {
+            true => DisconnectReason::ServerClosing,
+            false => DisconnectReason::ClientClosing,
+        });
+    }
+
+    fn close(&self, reason: DisconnectReason) {
+        if !self.link.closed.swap(true, Ordering::AcqRel) {
+            let _ = (self.link.to_server)(Packet::Close(reason.clone()));
+            let _ = (self.link.to_client)(Packet::Close(reason));
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoopbackClientsideTransport(LoopbackTransporter);
+
+impl LoopbackClientsideTransport {
+    pub fn send<M>(&self, message: M::ClientMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        self.0.send_frame(&Frame::Message(&M::from(message)))
+    }
+
+    pub fn blind_send<M>(&self, message: M::ClientMessage)
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        let _ = self.send::<M>(message);
+    }
+
+    pub fn send_with<M>(
+        &self,
+        message: M::ClientMessage,
+        _delivery: Delivery,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        self.send::<M>(message)
+    }
+
+    pub fn request<M>(
+        &self,
+        message: M::ClientMessage,
+        timeout: Duration,
+    ) -> Result<RpcHandle<M>, io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize + 'static,
+        M::ServerMessage: TryFrom<M>,
+    {
+        let handle = RpcHandle::register(&self.0.requests, timeout);
+        (self.0).send_frame(&Frame::Request(handle.id(), &M::from(message)))?;
+        Ok(handle)
+    }
+}
+
+impl Deref for LoopbackClientsideTransport {
+    type Target = LoopbackTransporter;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl ClientTransport for LoopbackClientsideTransport {
+    fn connection_id(&self) -> ConnectionId {
+        self.0.connection_id()
+    }
+
+    fn send<M>(&self, message: M::ClientMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        LoopbackClientsideTransport::send::<M>(self, message)
+    }
+
+    fn send_with<M>(&self, message: M::ClientMessage, delivery: Delivery) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        LoopbackClientsideTransport::send_with::<M>(self, message, delivery)
+    }
+
+    fn request<M>(
+        &self,
+        message: M::ClientMessage,
+        timeout: Duration,
+    ) -> Result<RpcHandle<M>, io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize + 'static,
+        M::ServerMessage: TryFrom<M>,
+    {
+        LoopbackClientsideTransport::request::<M>(self, message, timeout)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoopbackServersideTransport(LoopbackTransporter);
+
+impl LoopbackServersideTransport {
+    pub fn send<M>(&self, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.0.send_frame(&Frame::Message(&M::from(message)))
+    }
+
+    pub fn blind_send<M>(&self, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let _ = self.send::<M>(message);
+    }
+
+    pub fn send_with<M>(
+        &self,
+        message: M::ServerMessage,
+        _delivery: Delivery,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.send::<M>(message)
+    }
+
+    pub fn respond<M>(&self, id: RequestId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.0.send_frame(&Frame::Response(id, &M::from(message)))
+    }
+}
+
+impl Deref for LoopbackServersideTransport {
+    type Target = LoopbackTransporter;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl ServerTransport for LoopbackServersideTransport {
+    fn connection_id(&self) -> ConnectionId {
+        self.0.connection_id()
+    }
+
+    fn send<M>(&self, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        LoopbackServersideTransport::send::<M>(self, message)
+    }
+
+    fn send_with<M>(&self, message: M::ServerMessage, delivery: Delivery) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        LoopbackServersideTransport::send_with::<M>(self, message, delivery)
+    }
+
+    fn respond<M>(&self, id: RequestId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        LoopbackServersideTransport::respond::<M>(self, id, message)
+    }
+}
+
+type LoopbackConnections = Arc<Mutex<HashMap<ConnectionId, LoopbackServersideTransport>>>;
+
+pub struct LoopbackServer {
+    connections: LoopbackConnections,
+    next_id: AtomicU64,
+    accept: Box<dyn Fn(Peer) -> Inbox + Send + Sync>,
+}
+
+impl LoopbackServer {
+    pub fn start<M>(
+        event_sender: Sender<impl From<LoopbackServerNetworkEvent<M>> + Send + 'static>,
+    ) -> Self
+    where
+        M: ClientServerMessage + DeserializeOwned + 'static,
+        M::ClientMessage: TryFrom<M>,
+    {
+        let connections = LoopbackConnections::default();
+        let accept = {
+            let connections = connections.clone();
+            move |peer: Peer| -> Inbox {
+                let event_sender = event_sender.clone();
+                let connections = connections.clone();
+                Arc::new(move |packet| {
+                    let event = match packet {
+                        Packet::Connect(transporter) => {
+                            let transport = LoopbackServersideTransport(transporter);
+                            (connections.lock().unwrap()).insert(peer.id, transport.clone());
+                            NetworkEvent::Connect {
+                                transport,
+                                my_socket_addr: LOOPBACK_ADDR,
+                            }
+                        }
+                        Packet::Frame(frame) => match Bincode.decode::<Frame<M>>(&frame)? {
+                            Frame::Message(message) => {
+                                NetworkEvent::Message(client_message(message)?)
+                            }
+                            Frame::Request(id, message) => NetworkEvent::Request {
+                                id,
+                                message: client_message(message)?,
+                            },
+                            _ => {
+                                debug!("dropping unexpected frame from {}", peer.id);
+                                return Ok(());
+                            }
+                        },
+                        Packet::Close(reason) => {
+                            connections.lock().unwrap().remove(&peer.id);
+                            NetworkEvent::Disconnect(reason)
+                        }
+                    };
+                    (event_sender.send(ServerEvent::Connection(peer, event).into()))
+                        .map_err(channel_closed)
+                })
+            }
+        };
+        LoopbackServer {
+            connections,
+            next_id: AtomicU64::new(1),
+            accept: Box::new(accept),
+        }
+    }
+
+    pub fn connect<M>(
+        &self,
+        event_sender: Sender<impl From<LoopbackClientNetworkEvent<M>> + Send + 'static>,
+    ) -> LoopbackClient
+    where
+        M: ClientServerMessage + DeserializeOwned + Send + 'static,
+        M::ServerMessage: TryFrom<M>,
+    {
+        let peer = Peer {
+            id: ConnectionId(self.next_id.fetch_add(1, Ordering::Relaxed)),
+            addr: LOOPBACK_ADDR,
+        };
+        let requests = Arc::new(PendingRequests::default());
+        let to_client: Inbox = {
+            let requests = requests.clone();
+            Arc::new(move |packet| {
+                let event = match packet {
+                    Packet::Connect(transporter) => NetworkEvent::Connect {
+                        transport: LoopbackClientsideTransport(transporter),
+                        my_socket_addr: LOOPBACK_ADDR,
+                    },
+                    Packet::Frame(frame) => match Bincode.decode::<Frame<M>>(&frame)? {
+                        Frame::Message(message) => NetworkEvent::Message(server_message(message)?),
+                        Frame::Response(id, message) => {
+                            requests.resolve(id, message);
+                            return Ok(());
+                        }
+                        _ => {
+                            debug!("dropping unexpected frame from loopback server");
+                            return Ok(());
+                        }
+                    },
+                    Packet::Close(reason) => {
+                        requests.clear();
+                        NetworkEvent::Disconnect(reason)
+                    }
+                };
+                (event_sender.send(ClientEvent::Connection(event).into())).map_err(channel_closed)
+            })
+        };
+        let link = Arc::new(Link {
+            to_server: (self.accept)(peer),
+            to_client,
+            closed: AtomicBool::new(false),
+        });
+        let transporter = |serverside| LoopbackTransporter {
+            link: link.clone(),
+            serverside,
+            requests: requests.clone(),
+            connection_id: peer.id,
+        };
+        let _ = (link.to_server)(Packet::Connect(transporter(true)));
+        let _ = (link.to_client)(Packet::Connect(transporter(false)));
+        LoopbackClient {
+            transport: transporter(false),
+        }
+    }
+
+    pub fn connections(&self) -> Vec<Peer> {
+        let mut peers: Vec<_> = (self.connections.lock().unwrap().keys())
+            .map(|&id| Peer {
+                id,
+                addr: LOOPBACK_ADDR,
+            })
+            .collect();
+        peers.sort_by_key(|peer| peer.id);
+        peers
+    }
+
+    pub fn connection(&self, id: ConnectionId) -> Option<LoopbackServersideTransport> {
+        self.connections.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn send_to<M>(&self, id: ConnectionId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let Some(transport) = self.connection(id) else {
+            return Err(not_connected(id));
+        };
+        transport.send::<M>(message)
+    }
+
+    pub fn respond<M>(
+        &self,
+        id: ConnectionId,
+        request: RequestId,
+        message: M::ServerMessage,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let Some(transport) = self.connection(id) else {
+            return Err(not_connected(id));
+        };
+        transport.respond::<M>(request, message)
+    }
+
+    fn multicast<M>(&self, except: Option<ConnectionId>, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        let Ok(frame) = Bincode.encode(&Frame::Message(&M::from(message))) else {
+            return;
+        };
+        let transports: Vec<_> = (self.connections.lock().unwrap().iter())
+            .filter(|&(&id, _)| Some(id) != except)
+            .map(|(_, transport)| transport.clone())
+            .collect();
+        for transport in transports {
+            let _ = transport.send_encoded(frame.clone());
+        }
+    }
+
+    pub fn broadcast<M>(&self, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.multicast::<M>(None, message);
+    }
+
+    pub fn broadcast_except<M>(&self, except: ConnectionId, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        self.multicast::<M>(Some(except), message);
+    }
+
+    pub fn kick(&self, id: ConnectionId, reason: impl Into<String>) -> Result<(), io::Error> {
+        let Some(transport) = self.connection(id) else {
+            return Err(not_connected(id));
+        };
+        transport.close(DisconnectReason::Kicked(reason.into()));
+        Ok(())
+    }
+}
+
+impl MessageHost for LoopbackServer {
+    type Transport = LoopbackServersideTransport;
+
+    fn connections(&self) -> Vec<Peer> {
+        LoopbackServer::connections(self)
+    }
+
+    fn connection(&self, id: ConnectionId) -> Option<LoopbackServersideTransport> {
+        LoopbackServer::connection(self, id)
+    }
+
+    fn send_to<M>(&self, id: ConnectionId, message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        LoopbackServer::send_to::<M>(self, id, message)
+    }
+
+    fn respond<M>(
+        &self,
+        id: ConnectionId,
+        request: RequestId,
+        message: M::ServerMessage,
+    ) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        LoopbackServer::respond::<M>(self, id, request, message)
+    }
+
+    fn broadcast<M>(&self, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        LoopbackServer::broadcast::<M>(self, message)
+    }
+
+    fn broadcast_except<M>(&self, except: ConnectionId, message: M::ServerMessage)
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        LoopbackServer::broadcast_except::<M>(self, except, message)
+    }
+
+    fn kick(&self, id: ConnectionId, reason: impl Into<String>) -> Result<(), io::Error> {
+        LoopbackServer::kick(self, id, reason)
+    }
+}
+
+impl Drop for LoopbackServer {
+    fn drop(&mut self) {
+        let transports: Vec<_> = (self.connections.lock().unwrap().values())
+            .cloned()
+            .collect();
+        for transport in transports {
+            transport.shutdown();
+        }
+    }
+}
+
+pub struct LoopbackClient {
+    transport: LoopbackTransporter,
+}
+
+impl Drop for LoopbackClient {
+    fn drop(&mut self) {
+        self.transport.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, sync::mpsc::channel};
+
+    use super::{LoopbackClientNetworkEvent, LoopbackServer, LoopbackServerNetworkEvent};
+    use crate::transport::{
+        ClientConfig, ClientEvent, ClientTransport, ConnectionId, DisconnectReason, MessageHost,
+        NetworkEvent, ServerConfig, ServerEvent,
+        test::{Message, TIMEOUT, accept, start_client, start_server},
+    };
+
+    fn exchange(
+        host: &impl MessageHost,
+        id: ConnectionId,
+        transport: &impl ClientTransport,
+    ) -> Result<(), io::Error> {
+        assert_eq!(transport.connection_id(), id);
+        transport.send::<Message>("ping".to_string())?;
+        host.send_to::<Message>(id, 7)?;
+        host.kick(id, "done")
+    }
+
+    #[test]
+    fn test_loopback() {
+        let (server_sender, server_events) = channel::<LoopbackServerNetworkEvent<Message>>();
+        let server = LoopbackServer::start::<Message>(server_sender);
+        let (client_sender, client_events) = channel::<LoopbackClientNetworkEvent<Message>>();
+        let client = server.connect::<Message>(client_sender);
+
+        let Ok(ServerEvent::Connection(peer, NetworkEvent::Connect { .. })) =
+            server_events.try_recv()
+        else {
+            panic!("server did not see the client connect");
+        };
+        let Ok(ClientEvent::Connection(NetworkEvent::Connect { transport, .. })) =
+            client_events.try_recv()
+        else {
+            panic!("client did not connect");
+        };
+        transport.send::<Message>("hello".to_string()).unwrap();
+        assert!(matches!(
+            server_events.try_recv(),
+            Ok(ServerEvent::Connection(_, NetworkEvent::Message(message))) if message == "hello"
+        ));
+        server.send_to::<Message>(peer.id, 42).unwrap();
+        assert!(matches!(
+            client_events.try_recv(),
+            Ok(ClientEvent::Connection(NetworkEvent::Message(42)))
+        ));
+
+        drop(client);
+        assert!(matches!(
+            server_events.try_recv(),
+            Ok(ServerEvent::Connection(
+                _,
+                NetworkEvent::Disconnect(DisconnectReason::ClientClosing)
+            ))
+        ));
+        assert!(matches!(
+            client_events.try_recv(),
+            Ok(ClientEvent::Connection(NetworkEvent::Disconnect(
+                DisconnectReason::ClientClosing
+            )))
+        ));
+        assert!(server.connections().is_empty());
+        assert!(transport.send::<Message>("again".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_shared_transport() {
+        let (server_sender, server_events) = channel::<LoopbackServerNetworkEvent<Message>>();
+        let server = LoopbackServer::start::<Message>(server_sender);
+        let (client_sender, client_events) = channel::<LoopbackClientNetworkEvent<Message>>();
+        let _client = server.connect::<Message>(client_sender);
+        let Ok(ServerEvent::Connection(peer, NetworkEvent::Connect { .. })) =
+            server_events.try_recv()
+        else {
+            panic!("server did not see the client connect");
+        };
+        let Ok(ClientEvent::Connection(NetworkEvent::Connect { transport, .. })) =
+            client_events.try_recv()
+        else {
+            panic!("client did not connect");
+        };
+        exchange(&server, peer.id, &transport).unwrap();
+        assert!(matches!(
+            server_events.try_recv(),
+            Ok(ServerEvent::Connection(_, NetworkEvent::Message(message))) if message == "ping"
+        ));
+        assert!(matches!(
+            client_events.try_recv(),
+            Ok(ClientEvent::Connection(NetworkEvent::Message(7)))
+        ));
+        assert!(matches!(
+            client_events.try_recv(),
+            Ok(ClientEvent::Connection(NetworkEvent::Disconnect(DisconnectReason::Kicked(reason))))
+                if reason == "done"
+        ));
+
+        let (server, server_events) = start_server(ServerConfig::default());
+        let (_client, client_events) = start_client(&server, ClientConfig::default());
+        let (peer, transport) = accept(&server_events, &client_events);
+        exchange(&server, peer.id, &transport).unwrap();
+        assert!(matches!(
+            server_events.recv_timeout(TIMEOUT),
+            Ok(ServerEvent::Connection(_, NetworkEvent::Message(message))) if message == "ping"
+        ));
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::Connection(NetworkEvent::Message(7)))
+        ));
+        assert!(matches!(
+            client_events.recv_timeout(TIMEOUT),
+            Ok(ClientEvent::Connection(NetworkEvent::Disconnect(DisconnectReason::Kicked(reason))))
+                if reason == "done"
+        ));
+    }
+}
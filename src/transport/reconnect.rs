@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use super::random_u64;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ReconnectPolicy {
+    Never,
+    Backoff {
+        initial_delay: Duration,
+        max_delay: Duration,
+        multiplier: f64,
+        jitter: f64,
+        max_attempts: Option<u32>,
+    },
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy::Backoff {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        let ReconnectPolicy::Backoff {
+            initial_delay,
+            max_delay,
+            multiplier,
+            jitter,
+            max_attempts,
+        } = *self
+        else {
+            return None;
+        };
+        if attempt == 0 || max_attempts.is_some_and(|max_attempts| attempt > max_attempts) {
+            return None;
+        }
+        let delay = (initial_delay.as_secs_f64() * multiplier.powi(attempt as i32 - 1))
+            .min(max_delay.as_secs_f64());
+        let spread = random_u64() as f64 / u64::MAX as f64 * 2.0 - 1.0;
+        Some(Duration::from_secs_f64(
+            (delay * (1.0 + jitter * spread)).max(0.0),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::ReconnectPolicy;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(ReconnectPolicy::Never.delay(1), None);
+
+        let policy = ReconnectPolicy::Backoff {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(350),
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: Some(4),
+        };
+        let delays: Vec<_> = (0..=5).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [None, Some(100), Some(200), Some(350), Some(350), None]
+                .map(|delay| delay.map(Duration::from_millis))
+        );
+
+        let jittery = ReconnectPolicy::Backoff {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            multiplier: 1.0,
+            jitter: 0.5,
+            max_attempts: None,
+        };
+        for attempt in 1..100 {
+            let delay = jittery.delay(attempt).unwrap();
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(150));
+        }
+    }
+}
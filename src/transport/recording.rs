@@ -0,0 +1,639 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        mpsc::{RecvTimeoutError, Sender, channel},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use super::{
+    ClientEvent, ClientServerMessage, ClientTransport, ConnectionId, Delivery, DisconnectReason,
+    Frame, NetworkEvent, Peer, RequestId, RpcHandle, ServerEvent, ServerTransport, client_message,
+    codec::{Bincode, Codec, CodecExt, builtin_codec},
+    replication::Replication,
+    server_message,
+    udp::decode_datagram_message,
+};
+
+const RECORDING_MAGIC: [u8; 4] = *b"GGRC";
+const RECORDING_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type ReplayServerNetworkEvent<M> =
+    ServerEvent<ReplayTransport, <M as ClientServerMessage>::ClientMessage>;
+pub type ReplayClientNetworkEvent<M> =
+    ClientEvent<ReplayTransport, <M as ClientServerMessage>::ServerMessage>;
+
+#[derive(Serialize, Deserialize)]
+struct RecordingHeader {
+    magic: [u8; 4],
+    protocol_id: String,
+    protocol_version: u32,
+    codec: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Recorded {
+    Frame(Vec<u8>),
+    Datagram(Vec<u8>),
+    Disconnect(DisconnectReason),
+    Connect { my_socket_addr: SocketAddr },
+    Resumed,
+    Suspended,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    elapsed: Duration,
+    peer: Peer,
+    event: Recorded,
+}
+
+fn write_entry(writer: &mut impl Write, entry: &impl Serialize) -> Result<(), io::Error> {
+    let buf = Bincode.encode(entry)?;
+    writer.write_all(&u64::to_le_bytes(buf.len() as u64))?;
+    writer.write_all(&buf)
+}
+
+fn read_entry<T>(reader: &mut impl Read) -> Result<Option<T>, io::Error>
+where
+    T: DeserializeOwned,
+{
+    let mut len_buf = [0u8; 8];
+    match reader.read_exact(&mut len_buf) {
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let len = u64::from_le_bytes(len_buf);
+    let mut buf = Vec::new();
+    if reader.take(len).read_to_end(&mut buf)? as u64 != len {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "Recording ends partway through a record",
+        ));
+    }
+    Bincode.decode(&buf).map(Some)
+}
+
+#[derive(Debug)]
+struct RecordingFile {
+    writer: BufWriter<File>,
+    last_flush: Instant,
+}
+
+impl RecordingFile {
+    fn write(&mut self, record: &Record) -> Result<(), io::Error> {
+        write_entry(&mut self.writer, record)?;
+        // flushing on every record would hold the shared lock across a syscall per frame
+        if self.last_flush.elapsed() >= RECORDING_FLUSH_INTERVAL {
+            self.writer.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Recording {
+    start: Instant,
+    file: Mutex<RecordingFile>,
+}
+
+impl Recording {
+    pub(super) fn create<M>(path: &Path, codec: &dyn Codec) -> Result<Arc<Self>, io::Error>
+    where
+        M: ClientServerMessage,
+    {
+        let mut file = BufWriter::new(File::create(path)?);
+        let header = RecordingHeader {
+            magic: RECORDING_MAGIC,
+            protocol_id: M::PROTOCOL_ID.to_string(),
+            protocol_version: M::PROTOCOL_VERSION,
+            codec: codec.name().to_string(),
+        };
+        write_entry(&mut file, &header)?;
+        file.flush()?;
+        Ok(Arc::new(Recording {
+            start: Instant::now(),
+            file: Mutex::new(RecordingFile {
+                writer: file,
+                last_flush: Instant::now(),
+            }),
+        }))
+    }
+
+    pub(super) fn recorder(self: &Arc<Self>, peer: Peer) -> Recorder {
+        Recorder {
+            recording: self.clone(),
+            peer,
+        }
+    }
+
+    fn record(&self, peer: Peer, event: Recorded) {
+        let record = Record {
+            elapsed: self.start.elapsed(),
+            peer,
+            event,
+        };
+        let mut file = self.file.lock().unwrap();
+        if let Err(err) = file.write(&record) {
+            error!("Unable to record network event from {peer}: {err}");
+        }
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        if let Err(err) = self.file.get_mut().unwrap().writer.flush() {
+            error!("Unable to finish network recording: {err}");
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Recorder {
+    recording: Arc<Recording>,
+    peer: Peer,
+}
+
+impl Recorder {
+    pub(super) fn frame(&self, payload: &[u8]) {
+        (self.recording).record(self.peer, Recorded::Frame(payload.to_vec()));
+    }
+
+    pub(super) fn datagram(&self, datagram: &[u8]) {
+        (self.recording).record(self.peer, Recorded::Datagram(datagram.to_vec()));
+    }
+
+    pub(super) fn connect(&self, my_socket_addr: SocketAddr) {
+        (self.recording).record(self.peer, Recorded::Connect { my_socket_addr });
+    }
+
+    pub(super) fn resumed(&self) {
+        (self.recording).record(self.peer, Recorded::Resumed);
+    }
+
+    pub(super) fn suspended(&self) {
+        (self.recording).record(self.peer, Recorded::Suspended);
+    }
+
+    pub(super) fn disconnect(&self, reason: &DisconnectReason) {
+        (self.recording).record(self.peer, Recorded::Disconnect(reason.clone()));
+    }
+}
+
+fn replayed() -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        "Cannot send over a replayed connection",
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayTransport {
+    peer: Peer,
+}
+
+impl ReplayTransport {
+    pub fn peer(&self) -> Peer {
+        self.peer
+    }
+
+    pub fn connection_id(&self) -> ConnectionId {
+        self.peer.id
+    }
+}
+
+impl ClientTransport for ReplayTransport {
+    fn connection_id(&self) -> ConnectionId {
+        self.peer.id
+    }
+
+    fn send<M>(&self, _message: M::ClientMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        Err(replayed())
+    }
+
+    fn send_with<M>(&self, _message: M::ClientMessage, _delivery: Delivery) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize,
+    {
+        Err(replayed())
+    }
+
+    fn request<M>(
+        &self,
+        _message: M::ClientMessage,
+        _timeout: Duration,
+    ) -> Result<RpcHandle<M>, io::Error>
+    where
+        M: ClientServerMessage + From<M::ClientMessage> + Serialize + 'static,
+        M::ServerMessage: TryFrom<M>,
+    {
+        Err(replayed())
+    }
+}
+
+impl ServerTransport for ReplayTransport {
+    fn connection_id(&self) -> ConnectionId {
+        self.peer.id
+    }
+
+    fn send<M>(&self, _message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        Err(replayed())
+    }
+
+    fn send_with<M>(&self, _message: M::ServerMessage, _delivery: Delivery) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        Err(replayed())
+    }
+
+    fn respond<M>(&self, _id: RequestId, _message: M::ServerMessage) -> Result<(), io::Error>
+    where
+        M: ClientServerMessage + From<M::ServerMessage> + Serialize,
+    {
+        Err(replayed())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplaySpeed {
+    #[default]
+    RealTime,
+    FastForward,
+}
+
+pub struct Replay {
+    thread: Option<JoinHandle<()>>,
+    thread_kill: Sender<()>,
+}
+
+impl Replay {
+    fn open<M>(
+        path: &Path,
+        codec: Option<Arc<dyn Codec>>,
+    ) -> Result<(BufReader<File>, Arc<dyn Codec>), io::Error>
+    where
+        M: ClientServerMessage,
+    {
+        let mut reader = BufReader::new(File::open(path)?);
+        let header = match read_entry::<RecordingHeader>(&mut reader) {
+            Ok(Some(header)) if header.magic == RECORDING_MAGIC => header,
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} is not a network recording", path.display()),
+                ));
+            }
+        };
+        if header.protocol_id != M::PROTOCOL_ID || header.protocol_version < M::MIN_PROTOCOL_VERSION
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Recording was made with protocol {:?} version {}",
+                    header.protocol_id, header.protocol_version
+                ),
+            ));
+        }
+        let codec = match codec {
+            Some(codec) if codec.name() == header.codec => codec,
+            Some(codec) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Recording was encoded with {}, not {}",
+                        header.codec,
+                        codec.name()
+                    ),
+                ));
+            }
+            None => builtin_codec(&header.codec).ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Recording was encoded with unknown codec {:?}",
+                        header.codec
+                    ),
+                )
+            })?,
+        };
+        Ok((reader, codec))
+    }
+
+    fn spawn<E>(
+        event_sender: Sender<E>,
+        mut reader: BufReader<File>,
+        speed: ReplaySpeed,
+        mut replay: impl FnMut(Record) -> Result<Option<E>, io::Error> + Send + 'static,
+    ) -> Self
+    where
+        E: Send + 'static,
+    {
+        let (thread_kill, deathswitch) = channel();
+        let thread = thread::spawn(move || {
+            let start = Instant::now();
+            loop {
+                let record = match read_entry::<Record>(&mut reader) {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    Err(err) => {
+                        error!("Unable to read network recording: {err}");
+                        break;
+                    }
+                };
+                let delay = match speed {
+                    ReplaySpeed::RealTime => record.elapsed.saturating_sub(start.elapsed()),
+                    ReplaySpeed::FastForward => Duration::ZERO,
+                };
+                if deathswitch.recv_timeout(delay) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+                let peer = record.peer;
+                match replay(record) {
+                    Ok(Some(event)) => {
+                        if event_sender.send(event).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => debug!("skipping recorded event from {peer}: {err}"),
+                }
+            }
+            debug!("replay finished");
+        });
+        Replay {
+            thread: Some(thread),
+            thread_kill,
+        }
+    }
+
+    pub fn server<M>(
+        event_sender: Sender<impl From<ReplayServerNetworkEvent<M>> + Send + 'static>,
+        path: impl AsRef<Path>,
+        speed: ReplaySpeed,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ClientMessage: TryFrom<M>,
+    {
+        Self::replay_server::<M>(event_sender, path.as_ref(), speed, None)
+    }
+
+    pub fn server_with_codec<M>(
+        event_sender: Sender<impl From<ReplayServerNetworkEvent<M>> + Send + 'static>,
+        path: impl AsRef<Path>,
+        speed: ReplaySpeed,
+        codec: Arc<dyn Codec>,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ClientMessage: TryFrom<M>,
+    {
+        Self::replay_server::<M>(event_sender, path.as_ref(), speed, Some(codec))
+    }
+
+    fn replay_server<M>(
+        event_sender: Sender<impl From<ReplayServerNetworkEvent<M>> + Send + 'static>,
+        path: &Path,
+        speed: ReplaySpeed,
+        codec: Option<Arc<dyn Codec>>,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ClientMessage: TryFrom<M>,
+    {
+        let (reader, encoding) = Self::open::<M>(path, codec)?;
+        Ok(Self::spawn(event_sender, reader, speed, move |record| {
+            let event = match record.event {
+                Recorded::Frame(payload) => match encoding.decode::<Frame<M>>(&payload)? {
+                    Frame::Message(message) => NetworkEvent::Message(client_message(message)?),
+                    Frame::Request(id, message) => NetworkEvent::Request {
+                        id,
+                        message: client_message(message)?,
+                    },
+                    _ => return Ok(None),
+                },
+                Recorded::Datagram(datagram) => {
+                    let message = decode_datagram_message::<M>(&*encoding, &datagram)?;
+                    NetworkEvent::Message(client_message(message)?)
+                }
+                Recorded::Connect { my_socket_addr } => NetworkEvent::Connect {
+                    transport: ReplayTransport { peer: record.peer },
+                    my_socket_addr,
+                },
+                Recorded::Resumed => NetworkEvent::Resumed {
+                    transport: ReplayTransport { peer: record.peer },
+                },
+                Recorded::Suspended => NetworkEvent::Suspended,
+                Recorded::Disconnect(reason) => NetworkEvent::Disconnect(reason),
+            };
+            Ok(Some(ServerEvent::Connection(record.peer, event).into()))
+        }))
+    }
+
+    pub fn client<M>(
+        event_sender: Sender<impl From<ReplayClientNetworkEvent<M>> + Send + 'static>,
+        path: impl AsRef<Path>,
+        speed: ReplaySpeed,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ServerMessage: TryFrom<M>,
+    {
+        Self::replay_client::<M>(event_sender, path.as_ref(), speed, None)
+    }
+
+    pub fn client_with_codec<M>(
+        event_sender: Sender<impl From<ReplayClientNetworkEvent<M>> + Send + 'static>,
+        path: impl AsRef<Path>,
+        speed: ReplaySpeed,
+        codec: Arc<dyn Codec>,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ServerMessage: TryFrom<M>,
+    {
+        Self::replay_client::<M>(event_sender, path.as_ref(), speed, Some(codec))
+    }
+
+    fn replay_client<M>(
+        event_sender: Sender<impl From<ReplayClientNetworkEvent<M>> + Send + 'static>,
+        path: &Path,
+        speed: ReplaySpeed,
+        codec: Option<Arc<dyn Codec>>,
+    ) -> Result<Self, io::Error>
+    where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ServerMessage: TryFrom<M>,
+    {
+        let (reader, encoding) = Self::open::<M>(path, codec)?;
+        let mut replications: HashMap<_, Replication> = HashMap::new();
+        Ok(Self::spawn(event_sender, reader, speed, move |record| {
+            let event = match record.event {
+                Recorded::Frame(payload) => match encoding.decode::<Frame<M>>(&payload)? {
+                    Frame::Message(message) => NetworkEvent::Message(server_message(message)?),
+                    Frame::Snapshot(snapshot) => {
+                        let replication = replications.entry(record.peer.id).or_default();
+                        NetworkEvent::State(server_message(replication.receive::<M>(snapshot)?)?)
+                    }
+                    _ => return Ok(None),
+                },
+                Recorded::Datagram(datagram) => {
+                    let message = decode_datagram_message::<M>(&*encoding, &datagram)?;
+                    NetworkEvent::Message(server_message(message)?)
+                }
+                Recorded::Connect { my_socket_addr } => NetworkEvent::Connect {
+                    transport: ReplayTransport { peer: record.peer },
+                    my_socket_addr,
+                },
+                Recorded::Resumed => NetworkEvent::Resumed {
+                    transport: ReplayTransport { peer: record.peer },
+                },
+                Recorded::Suspended => NetworkEvent::Suspended,
+                Recorded::Disconnect(reason) => {
+                    replications.remove(&record.peer.id);
+                    NetworkEvent::Disconnect(reason)
+                }
+            };
+            Ok(Some(ClientEvent::Connection(event).into()))
+        }))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+}
+
+impl Drop for Replay {
+    fn drop(&mut self) {
+        let _ = self.thread_kill.send(());
+        self.thread.take().unwrap().join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, mpsc::channel},
+    };
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{Recording, Replay, ReplayServerNetworkEvent, ReplaySpeed};
+    use crate::transport::{
+        ClientServerMessage, ConnectionId, DisconnectReason, Frame, NetworkEvent, Peer, RequestId,
+        ServerEvent, ServerTransport,
+        codec::{CodecExt, Json, MessagePack},
+    };
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Message(String);
+
+    impl ClientServerMessage for Message {
+        type ClientMessage = String;
+        type ServerMessage = String;
+
+        const PROTOCOL_ID: &'static str = "recording-test";
+    }
+
+    impl TryFrom<Message> for String {
+        type Error = ();
+
+        fn try_from(message: Message) -> Result<Self, Self::Error> {
+            Ok(message.0)
+        }
+    }
+
+    impl From<String> for Message {
+        fn from(message: String) -> Self {
+            Message(message)
+        }
+    }
+
+    struct Other;
+
+    impl ClientServerMessage for Other {
+        type ClientMessage = ();
+        type ServerMessage = ();
+
+        const PROTOCOL_ID: &'static str = "other";
+    }
+
+    #[test]
+    fn test_replay() {
+        let path = std::env::temp_dir().join(format!("ggnr-test-{}.rec", std::process::id()));
+        let encoding = MessagePack;
+        let peer = Peer {
+            id: ConnectionId(3),
+            addr: SocketAddr::from(([127, 0, 0, 1], 4000)),
+        };
+        let frame = |frame: Frame<Message>| encoding.encode(&frame).unwrap();
+        {
+            let recorder = Recording::create::<Message>(&path, &encoding)
+                .unwrap()
+                .recorder(peer);
+            recorder.connect(SocketAddr::from(([127, 0, 0, 1], 5000)));
+            recorder.frame(&frame(Frame::Message(Message("hello".into()))));
+            recorder.frame(&frame(Frame::Ping(1)));
+            recorder.suspended();
+            recorder.resumed();
+            recorder.frame(&frame(Frame::Request(RequestId(7), Message("ask".into()))));
+            recorder.disconnect(&DisconnectReason::ClientClosing);
+        }
+
+        let (event_sender, events) = channel::<ReplayServerNetworkEvent<Message>>();
+        let _replay =
+            Replay::server::<Message>(event_sender, &path, ReplaySpeed::FastForward).unwrap();
+        let events: Vec<_> = (events.iter())
+            .map(|event| match event {
+                ServerEvent::Connection(peer, event) => (peer, event),
+                ServerEvent::ListenerError(err) => panic!("Unexpected listener error: {err}"),
+            })
+            .collect();
+        assert_eq!(events.len(), 6);
+        assert!(events.iter().all(|(replayed, _)| *replayed == peer));
+        let NetworkEvent::Connect {
+            transport,
+            my_socket_addr,
+        } = &events[0].1
+        else {
+            panic!("Expected connect, got {:?}", events[0].1);
+        };
+        assert_eq!(*my_socket_addr, SocketAddr::from(([127, 0, 0, 1], 5000)));
+        assert_eq!(transport.peer(), peer);
+        assert!(transport.send::<Message>("reply".into()).is_err());
+        assert!(matches!(&events[1].1, NetworkEvent::Message(message) if message == "hello"));
+        assert!(matches!(events[2].1, NetworkEvent::Suspended));
+        assert!(matches!(events[3].1, NetworkEvent::Resumed { .. }));
+        assert!(matches!(
+            &events[4].1,
+            NetworkEvent::Request { id: RequestId(7), message } if message == "ask"
+        ));
+        assert!(matches!(
+            events[5].1,
+            NetworkEvent::Disconnect(DisconnectReason::ClientClosing)
+        ));
+
+        assert!(Replay::open::<Other>(&path, None).is_err());
+        assert!(Replay::open::<Message>(&path, Some(Arc::new(Json))).is_err());
+        assert!(Replay::open::<Message>(&path, Some(Arc::new(MessagePack))).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, ErrorKind},
+};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+const MAX_SNAPSHOT_HISTORY: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Delta {
+    Replace(Value),
+    Remove,
+    Fields(BTreeMap<String, Delta>),
+    Elements(usize, BTreeMap<usize, Delta>),
+}
+
+fn diff(old: &Value, new: &Value) -> Option<Delta> {
+    match (old, new) {
+        _ if old == new => None,
+        (Value::Object(old), Value::Object(new)) => {
+            let removed = (old.keys())
+                .filter(|key| !new.contains_key(*key))
+                .map(|key| (key.clone(), Delta::Remove));
+            let changed = (new.iter()).filter_map(|(key, value)| {
+                let delta = match old.get(key) {
+                    Some(old) => diff(old, value)?,
+                    None => Delta::Replace(value.clone()),
+                };
+                Some((key.clone(), delta))
+            });
+            Some(Delta::Fields(removed.chain(changed).collect()))
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            let changed = (new.iter().enumerate())
+                .filter_map(|(i, value)| {
+                    let delta = match old.get(i) {
+                        Some(old) => diff(old, value)?,
+                        None => Delta::Replace(value.clone()),
+                    };
+                    Some((i, delta))
+                })
+                .collect();
+            Some(Delta::Elements(new.len(), changed))
+        }
+        _ => Some(Delta::Replace(new.clone())),
+    }
+}
+
+fn apply(value: &mut Value, delta: Delta) -> Result<(), io::Error> {
+    match (value, delta) {
+        (value, Delta::Replace(new)) => *value = new,
+        (Value::Object(fields), Delta::Fields(changed)) => {
+            for (key, delta) in changed {
+                match delta {
+                    Delta::Remove => {
+                        fields.remove(&key);
+                    }
+                    delta => apply(fields.entry(key).or_insert(Value::Null), delta)?,
+                }
+            }
+        }
+        (Value::Array(elements), Delta::Elements(len, changed)) => {
+            elements.resize(len, Value::Null);
+            for (i, delta) in changed {
+                let Some(element) = elements.get_mut(i) else {
+                    return Err(mismatched_delta());
+                };
+                apply(element, delta)?;
+            }
+        }
+        _ => return Err(mismatched_delta()),
+    }
+    Ok(())
+}
+
+fn mismatched_delta() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        "Snapshot delta does not match its baseline",
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Snapshot {
+    sequence: u64,
+    baseline: Option<u64>,
+    // encoded as json regardless of the codec, since not every codec can decode a `Value`
+    delta: Vec<u8>,
+}
+
+impl Snapshot {
+    pub(super) fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Replication {
+    sequence: u64,
+    acknowledged: Option<u64>,
+    sent: VecDeque<(u64, Value)>,
+    received: VecDeque<(u64, Value)>,
+}
+
+impl Replication {
+    pub(super) fn snapshot(&mut self, state: Value) -> Result<Snapshot, io::Error> {
+        self.sequence += 1;
+        let baseline = (self.acknowledged).and_then(|acknowledged| {
+            self.sent
+                .iter()
+                .find(|(sequence, _)| *sequence == acknowledged)
+        });
+        let delta = match baseline {
+            Some((_, old)) => diff(old, &state),
+            None => Some(Delta::Replace(state.clone())),
+        };
+        let snapshot = Snapshot {
+            sequence: self.sequence,
+            baseline: baseline.map(|(sequence, _)| *sequence),
+            delta: serde_json::to_vec(&delta)?,
+        };
+        self.sent.push_back((self.sequence, state));
+        while self.sent.len() > MAX_SNAPSHOT_HISTORY {
+            self.sent.pop_front();
+        }
+        Ok(snapshot)
+    }
+
+    pub(super) fn acknowledge(&mut self, sequence: u64) {
+        if self
+            .acknowledged
+            .is_some_and(|acknowledged| acknowledged >= sequence)
+        {
+            return;
+        }
+        self.acknowledged = Some(sequence);
+        self.sent.retain(|(sent, _)| *sent >= sequence);
+    }
+
+    pub(super) fn receive<M>(&mut self, snapshot: Snapshot) -> Result<M, io::Error>
+    where
+        M: DeserializeOwned,
+    {
+        let mut state = match snapshot.baseline {
+            Some(baseline) => (self.received.iter())
+                .find(|(sequence, _)| *sequence == baseline)
+                .map(|(_, state)| state.clone())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Missing baseline for snapshot {}", snapshot.sequence),
+                    )
+                })?,
+            None => Value::Null,
+        };
+        if let Some(delta) = serde_json::from_slice::<Option<Delta>>(&snapshot.delta)? {
+            apply(&mut state, delta)?;
+        }
+        let message = serde_json::from_value(state.clone())?;
+        if let Some(baseline) = snapshot.baseline {
+            self.received.retain(|(sequence, _)| *sequence >= baseline);
+        }
+        self.received.push_back((snapshot.sequence, state));
+        while self.received.len() > MAX_SNAPSHOT_HISTORY {
+            self.received.pop_front();
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{Replication, Snapshot};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Player {
+        name: String,
+        position: (f32, f32),
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct World {
+        tick: u64,
+        players: Vec<Player>,
+        scores: BTreeMap<String, u32>,
+    }
+
+    fn snapshot(replication: &mut Replication, world: &World) -> Snapshot {
+        (replication.snapshot(serde_json::to_value(world).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_replication() {
+        let mut server = Replication::default();
+        let mut client = Replication::default();
+        let mut world = World {
+            tick: 0,
+            players: (0..16)
+                .map(|i| Player {
+                    name: format!("player {i}"),
+                    position: (0.0, 0.0),
+                })
+                .collect(),
+            scores: BTreeMap::from([("a".into(), 0)]),
+        };
+
+        let full = snapshot(&mut server, &world);
+        let (full_sequence, full_len) = (full.sequence(), full.delta.len());
+        assert_eq!(client.receive::<World>(full).unwrap(), world);
+        server.acknowledge(full_sequence);
+
+        world.tick = 1;
+        world.players[0].position.0 = 2.5;
+        let delta = snapshot(&mut server, &world);
+        assert!(delta.delta.len() < full_len / 2);
+        assert_eq!(client.receive::<World>(delta).unwrap(), world);
+
+        world.tick = 2;
+        world.players.truncate(15);
+        world.scores = BTreeMap::from([("b".into(), 1)]);
+        let unacknowledged = snapshot(&mut server, &world);
+        assert_eq!(unacknowledged.baseline, Some(full_sequence));
+        assert_eq!(client.receive::<World>(unacknowledged).unwrap(), world);
+
+        let mut stranger = Replication::default();
+        let orphan = snapshot(&mut server, &world);
+        assert!(stranger.receive::<World>(orphan).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt::Display,
+    io::{self, ErrorKind},
+    marker::PhantomData,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError, channel},
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{ClientServerMessage, server_message};
+
+type Reply = Box<dyn Any + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RequestId(pub u64);
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct PendingRequests {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<RequestId, Sender<Reply>>>,
+}
+
+impl PendingRequests {
+    fn register(&self) -> (RequestId, Receiver<Reply>) {
+        let id = RequestId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (sender, receiver) = channel();
+        self.pending.lock().unwrap().insert(id, sender);
+        (id, receiver)
+    }
+
+    fn cancel(&self, id: RequestId) {
+        self.pending.lock().unwrap().remove(&id);
+    }
+
+    pub(super) fn resolve<M>(&self, id: RequestId, message: M) -> bool
+    where
+        M: Send + 'static,
+    {
+        let Some(sender) = self.pending.lock().unwrap().remove(&id) else {
+            return false;
+        };
+        sender.send(Box::new(message)).is_ok()
+    }
+
+    pub(super) fn clear(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+pub struct RpcHandle<M> {
+    id: RequestId,
+    requests: Arc<PendingRequests>,
+    receiver: Receiver<Reply>,
+    deadline: Instant,
+    _message: PhantomData<fn() -> M>,
+}
+
+impl<M> RpcHandle<M>
+where
+    M: ClientServerMessage + 'static,
+    M::ServerMessage: TryFrom<M>,
+{
+    pub(super) fn register(requests: &Arc<PendingRequests>, timeout: Duration) -> Self {
+        let (id, receiver) = requests.register();
+        RpcHandle {
+            id,
+            requests: requests.clone(),
+            receiver,
+            deadline: Instant::now() + timeout,
+            _message: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    fn reply(reply: Reply) -> Result<M::ServerMessage, io::Error> {
+        let Ok(message) = reply.downcast::<M>() else {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Response does not match the requested message type",
+            ));
+        };
+        server_message::<M>(*message)
+    }
+
+    fn timed_out(&self) -> io::Error {
+        io::Error::new(
+            ErrorKind::TimedOut,
+            format!("Request {} timed out", self.id),
+        )
+    }
+
+    fn closed(&self) -> io::Error {
+        io::Error::new(
+            ErrorKind::ConnectionAborted,
+            format!("Connection closed before request {} was answered", self.id),
+        )
+    }
+
+    pub fn try_recv(&self) -> Option<Result<M::ServerMessage, io::Error>> {
+        match self.receiver.try_recv() {
+            Ok(reply) => Some(Self::reply(reply)),
+            Err(TryRecvError::Empty) if Instant::now() >= self.deadline => {
+                Some(Err(self.timed_out()))
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(self.closed())),
+        }
+    }
+
+    pub fn wait(self) -> Result<M::ServerMessage, io::Error> {
+        let timeout = self.deadline.saturating_duration_since(Instant::now());
+        match self.receiver.recv_timeout(timeout) {
+            Ok(reply) => Self::reply(reply),
+            Err(RecvTimeoutError::Timeout) => Err(self.timed_out()),
+            Err(RecvTimeoutError::Disconnected) => Err(self.closed()),
+        }
+    }
+}
+
+impl<M> Drop for RpcHandle<M> {
+    fn drop(&mut self) {
+        self.requests.cancel(self.id);
+    }
+}
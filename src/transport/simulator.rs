@@ -0,0 +1,230 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io::{self, ErrorKind},
+    sync::{
+        Arc,
+        mpsc::{RecvTimeoutError, Sender, channel},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use super::random_u64;
+
+const MIN_REORDER_DELAY: Duration = Duration::from_millis(50);
+const MIN_RETRANSMIT_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetworkConditions {
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub bandwidth: Option<u64>,
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    pub reorder_rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum LinkKind {
+    Stream,
+    Datagram,
+}
+
+fn chance(rate: f64) -> bool {
+    rate > 0.0 && (random_u64() as f64 / u64::MAX as f64) < rate
+}
+
+struct Scheduler {
+    conditions: NetworkConditions,
+    kind: LinkKind,
+    link_free_at: Instant,
+    last_due: Instant,
+    sequence: u64,
+    queue: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+}
+
+impl Scheduler {
+    fn schedule(&mut self, packet: Vec<u8>) {
+        let conditions = self.conditions;
+        let datagram = self.kind == LinkKind::Datagram;
+        // a lost stream segment is retransmitted, holding up every frame behind it
+        let mut retransmit = Duration::ZERO;
+        if chance(conditions.drop_rate) {
+            if datagram {
+                return;
+            }
+            retransmit = (conditions.latency * 2).max(MIN_RETRANSMIT_DELAY);
+        }
+        let copies = if datagram && chance(conditions.duplicate_rate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let now = Instant::now();
+            let mut due = now;
+            if let Some(bandwidth) = conditions.bandwidth.filter(|&bandwidth| bandwidth > 0) {
+                let transmit = Duration::from_secs_f64(packet.len() as f64 / bandwidth as f64);
+                self.link_free_at = self.link_free_at.max(now) + transmit;
+                due = self.link_free_at;
+            }
+            due += conditions.latency
+                + (conditions.jitter).mul_f64(random_u64() as f64 / u64::MAX as f64)
+                + retransmit;
+            if datagram && chance(conditions.reorder_rate) {
+                due += conditions.latency.max(MIN_REORDER_DELAY);
+            } else {
+                due = due.max(self.last_due);
+                self.last_due = due;
+            }
+            self.sequence += 1;
+            self.queue
+                .push(Reverse((due, self.sequence, packet.clone())));
+        }
+    }
+
+    fn next_due(&self) -> Option<Duration> {
+        (self.queue.peek()).map(|Reverse((due, ..))| due.saturating_duration_since(Instant::now()))
+    }
+
+    fn pop_due(&mut self) -> Option<Vec<u8>> {
+        let Reverse((due, ..)) = self.queue.peek()?;
+        if *due > Instant::now() {
+            return None;
+        }
+        self.queue.pop().map(|Reverse((_, _, packet))| packet)
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct SimulatedLink {
+    sender: Sender<Vec<u8>>,
+}
+
+impl SimulatedLink {
+    pub(super) fn spawn(
+        conditions: NetworkConditions,
+        kind: LinkKind,
+        mut sink: impl FnMut(&[u8]) -> Result<(), io::Error> + Send + 'static,
+    ) -> Arc<Self> {
+        let (sender, receiver) = channel::<Vec<u8>>();
+        thread::spawn(move || {
+            let now = Instant::now();
+            let mut scheduler = Scheduler {
+                conditions,
+                kind,
+                link_free_at: now,
+                last_due: now,
+                sequence: 0,
+                queue: BinaryHeap::new(),
+            };
+            loop {
+                let received = match scheduler.next_due() {
+                    Some(timeout) => receiver.recv_timeout(timeout),
+                    None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(packet) => scheduler.schedule(packet),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+                while let Some(packet) = scheduler.pop_due() {
+                    if let Err(err) = sink(&packet) {
+                        debug!("simulated link closed: {err}");
+                        return;
+                    }
+                }
+            }
+        });
+        Arc::new(SimulatedLink { sender })
+    }
+
+    pub(super) fn submit(&self, packet: Vec<u8>) -> Result<(), io::Error> {
+        (self.sender.send(packet))
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "Simulated link closed"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BinaryHeap,
+        time::{Duration, Instant},
+    };
+
+    use super::{LinkKind, MIN_RETRANSMIT_DELAY, NetworkConditions, Scheduler};
+
+    fn scheduler(conditions: NetworkConditions, kind: LinkKind) -> Scheduler {
+        let now = Instant::now();
+        Scheduler {
+            conditions,
+            kind,
+            link_free_at: now,
+            last_due: now,
+            sequence: 0,
+            queue: BinaryHeap::new(),
+        }
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let lossy = NetworkConditions {
+            drop_rate: 1.0,
+            duplicate_rate: 1.0,
+            reorder_rate: 1.0,
+            ..Default::default()
+        };
+        let mut dropping = scheduler(lossy, LinkKind::Datagram);
+        dropping.schedule(vec![1]);
+        assert!(dropping.queue.is_empty());
+
+        let mut duplicating = scheduler(
+            NetworkConditions {
+                duplicate_rate: 1.0,
+                ..Default::default()
+            },
+            LinkKind::Datagram,
+        );
+        duplicating.schedule(vec![1]);
+        assert_eq!(duplicating.pop_due(), Some(vec![1]));
+        assert_eq!(duplicating.pop_due(), Some(vec![1]));
+        assert_eq!(duplicating.pop_due(), None);
+
+        let start = Instant::now();
+        let mut retransmitting = scheduler(lossy, LinkKind::Stream);
+        retransmitting.schedule(vec![1]);
+        retransmitting.conditions.drop_rate = 0.0;
+        retransmitting.schedule(vec![2]);
+        assert_eq!(retransmitting.queue.len(), 2);
+        assert_eq!(retransmitting.pop_due(), None);
+        let mut delivered = Vec::new();
+        while !retransmitting.queue.is_empty() {
+            std::thread::sleep(retransmitting.next_due().unwrap());
+            delivered.extend(retransmitting.pop_due());
+        }
+        assert!(start.elapsed() >= MIN_RETRANSMIT_DELAY);
+        assert_eq!(delivered, vec![vec![1], vec![2]]);
+
+        let mut jittery = scheduler(
+            NetworkConditions {
+                latency: Duration::from_millis(20),
+                jitter: Duration::from_millis(20),
+                ..Default::default()
+            },
+            LinkKind::Stream,
+        );
+        for i in 0..32 {
+            jittery.schedule(vec![i]);
+        }
+        assert_eq!(jittery.pop_due(), None);
+        let mut delivered = Vec::new();
+        while !jittery.queue.is_empty() {
+            std::thread::sleep(jittery.next_due().unwrap());
+            delivered.extend(jittery.pop_due());
+        }
+        assert_eq!(delivered, (0..32).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+}
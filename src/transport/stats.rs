@@ -0,0 +1,278 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        Mutex,
+        mpsc::{RecvTimeoutError, Sender, channel},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Serialize;
+
+use super::{ConnectionId, MessageClient, MessageServer};
+use crate::{csv_recorder::CsvRecorder, util::SystemTimeExt};
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum FrameKind {
+    Handshake,
+    Message,
+    Request,
+    Response,
+    Snapshot,
+    SnapshotAck,
+    Ping,
+    Pong,
+    Goodbye,
+    Datagram,
+}
+
+impl FrameKind {
+    // heartbeats, acks and the handshake are overhead, not messages
+    fn is_message(self) -> bool {
+        matches!(
+            self,
+            FrameKind::Message
+                | FrameKind::Request
+                | FrameKind::Response
+                | FrameKind::Snapshot
+                | FrameKind::Datagram
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct FrameStats {
+    pub sent: u64,
+    pub received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ConnectionStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub send_rate: f64,
+    pub receive_rate: f64,
+    pub largest_frame: usize,
+    pub frames: BTreeMap<FrameKind, FrameStats>,
+}
+
+#[derive(Debug)]
+struct Counters {
+    stats: ConnectionStats,
+    window_start: Instant,
+    window_sent: u64,
+    window_received: u64,
+}
+
+impl Counters {
+    fn roll(&mut self) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        self.stats.send_rate = self.window_sent as f64 / elapsed.as_secs_f64();
+        self.stats.receive_rate = self.window_received as f64 / elapsed.as_secs_f64();
+        self.window_start = Instant::now();
+        self.window_sent = 0;
+        self.window_received = 0;
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct TransferStats(Mutex<Counters>);
+
+impl TransferStats {
+    pub(super) fn new() -> Self {
+        TransferStats(Mutex::new(Counters {
+            stats: ConnectionStats::default(),
+            window_start: Instant::now(),
+            window_sent: 0,
+            window_received: 0,
+        }))
+    }
+
+    pub(super) fn sent(&self, kind: FrameKind, bytes: usize) {
+        let mut counters = self.0.lock().unwrap();
+        counters.roll();
+        let stats = &mut counters.stats;
+        stats.bytes_sent += bytes as u64;
+        stats.messages_sent += kind.is_message() as u64;
+        stats.largest_frame = stats.largest_frame.max(bytes);
+        let frame = stats.frames.entry(kind).or_default();
+        frame.sent += 1;
+        frame.bytes_sent += bytes as u64;
+        counters.window_sent += bytes as u64;
+    }
+
+    pub(super) fn received(&self, kind: FrameKind, bytes: usize) {
+        let mut counters = self.0.lock().unwrap();
+        counters.roll();
+        let stats = &mut counters.stats;
+        stats.bytes_received += bytes as u64;
+        stats.messages_received += kind.is_message() as u64;
+        stats.largest_frame = stats.largest_frame.max(bytes);
+        let frame = stats.frames.entry(kind).or_default();
+        frame.received += 1;
+        frame.bytes_received += bytes as u64;
+        counters.window_received += bytes as u64;
+    }
+
+    pub(super) fn snapshot(&self) -> ConnectionStats {
+        let mut counters = self.0.lock().unwrap();
+        counters.roll();
+        counters.stats.clone()
+    }
+}
+
+#[derive(Serialize)]
+struct StatsRecord {
+    time: String,
+    connection: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    messages_sent: u64,
+    messages_received: u64,
+    send_rate: f64,
+    receive_rate: f64,
+    largest_frame: usize,
+}
+
+#[derive(Serialize)]
+struct FrameStatsRecord {
+    time: String,
+    connection: u64,
+    kind: FrameKind,
+    sent: u64,
+    received: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+pub struct StatsRecorder {
+    thread: Option<JoinHandle<()>>,
+    thread_kill: Sender<()>,
+}
+
+impl StatsRecorder {
+    pub fn server(
+        server: &MessageServer,
+        recorder: CsvRecorder,
+        subpath: impl Into<PathBuf>,
+        interval: Duration,
+    ) -> Self {
+        let connections = server.connections.clone();
+        Self::spawn(recorder, subpath.into(), interval, move || {
+            let connections = connections.lock().unwrap();
+            (connections.transports.iter())
+                .map(|(&id, transport)| (id, transport.stats()))
+                .collect()
+        })
+    }
+
+    pub fn client(
+        client: &MessageClient,
+        recorder: CsvRecorder,
+        subpath: impl Into<PathBuf>,
+        interval: Duration,
+    ) -> Self {
+        let connection = client.connection.clone();
+        Self::spawn(recorder, subpath.into(), interval, move || {
+            let connection = connection.lock().unwrap();
+            (connection.transport.iter())
+                .map(|transport| (transport.connection_id(), transport.stats()))
+                .collect()
+        })
+    }
+
+    fn spawn(
+        recorder: CsvRecorder,
+        subpath: PathBuf,
+        interval: Duration,
+        source: impl Fn() -> Vec<(ConnectionId, ConnectionStats)> + Send + 'static,
+    ) -> Self {
+        let (thread_kill, deathswitch) = channel();
+        let mut frames_subpath = subpath.clone().into_os_string();
+        frames_subpath.push("_frames");
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = deathswitch.recv_timeout(interval) {
+                let time = SystemTime::now().strftime("%Y-%m-%d %H:%M:%S%.3f");
+                let mut stats = source();
+                stats.sort_by_key(|(id, _)| *id);
+                for (id, stats) in stats {
+                    let record = StatsRecord {
+                        time: time.clone(),
+                        connection: id.0,
+                        bytes_sent: stats.bytes_sent,
+                        bytes_received: stats.bytes_received,
+                        messages_sent: stats.messages_sent,
+                        messages_received: stats.messages_received,
+                        send_rate: stats.send_rate,
+                        receive_rate: stats.receive_rate,
+                        largest_frame: stats.largest_frame,
+                    };
+                    recorder.record(&subpath, record);
+                    for (kind, frame) in stats.frames {
+                        let record = FrameStatsRecord {
+                            time: time.clone(),
+                            connection: id.0,
+                            kind,
+                            sent: frame.sent,
+                            received: frame.received,
+                            bytes_sent: frame.bytes_sent,
+                            bytes_received: frame.bytes_received,
+                        };
+                        recorder.record(&frames_subpath, record);
+                    }
+                }
+            }
+        });
+        StatsRecorder {
+            thread: Some(thread),
+            thread_kill,
+        }
+    }
+}
+
+impl Drop for StatsRecorder {
+    fn drop(&mut self) {
+        let _ = self.thread_kill.send(());
+        self.thread.take().unwrap().join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FrameKind, FrameStats, TransferStats};
+
+    #[test]
+    fn test_transfer_stats() {
+        let stats = TransferStats::new();
+        stats.sent(FrameKind::Message, 100);
+        stats.sent(FrameKind::Ping, 20);
+        stats.sent(FrameKind::Message, 50);
+        stats.received(FrameKind::Request, 300);
+        stats.received(FrameKind::Pong, 20);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.bytes_sent, 170);
+        assert_eq!(snapshot.bytes_received, 320);
+        assert_eq!(snapshot.messages_sent, 2);
+        assert_eq!(snapshot.messages_received, 1);
+        assert_eq!(snapshot.largest_frame, 300);
+        let message = FrameStats {
+            sent: 2,
+            bytes_sent: 150,
+            ..Default::default()
+        };
+        assert_eq!(snapshot.frames[&FrameKind::Message], message);
+        assert_eq!(snapshot.frames[&FrameKind::Pong].received, 1);
+        assert!(!snapshot.frames.contains_key(&FrameKind::Response));
+    }
+}
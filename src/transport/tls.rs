@@ -0,0 +1,381 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+use rustls::{
+    ClientConnection, Connection, DigitallySignedStruct, ServerConnection, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, WebPkiSupportedAlgorithms},
+    pki_types::{
+        CertificateDer, PrivateKeyDer, ServerName, UnixTime,
+        pem::{self, PemObject},
+    },
+};
+
+const TLS_READ_BUFFER_SIZE: usize = 16 * 1024;
+
+fn tls_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+fn pem_error(err: pem::Error) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("Invalid pem data: {err:?}"),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsServer(Arc<rustls::ServerConfig>);
+
+impl TlsServer {
+    pub fn new(config: Arc<rustls::ServerConfig>) -> Self {
+        TlsServer(config)
+    }
+
+    pub fn from_der(
+        certificate_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+    ) -> Result<Self, io::Error> {
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificate_chain, private_key)
+            .map_err(tls_error)?;
+        Ok(TlsServer(Arc::new(config)))
+    }
+
+    pub fn from_pem(certificate_chain: &[u8], private_key: &[u8]) -> Result<Self, io::Error> {
+        let certificate_chain = CertificateDer::pem_slice_iter(certificate_chain)
+            .collect::<Result<_, _>>()
+            .map_err(pem_error)?;
+        let private_key = PrivateKeyDer::from_pem_slice(private_key).map_err(pem_error)?;
+        Self::from_der(certificate_chain, private_key)
+    }
+
+    pub fn self_signed(
+        subject_alt_names: impl Into<Vec<String>>,
+    ) -> Result<(Self, CertificateDer<'static>), io::Error> {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(subject_alt_names).map_err(tls_error)?;
+        let certificate = cert.der().clone();
+        let private_key = PrivateKeyDer::try_from(key_pair.serialize_der()).map_err(tls_error)?;
+        Ok((
+            Self::from_der(vec![certificate.clone()], private_key)?,
+            certificate,
+        ))
+    }
+
+    pub(super) fn accept(&self, stream: &TcpStream) -> Result<TlsSession, io::Error> {
+        let connection = ServerConnection::new(self.0.clone()).map_err(tls_error)?;
+        TlsSession::establish(connection.into(), stream)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsClient {
+    config: Arc<rustls::ClientConfig>,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl TlsClient {
+    pub fn new(config: Arc<rustls::ClientConfig>, server_name: ServerName<'static>) -> Self {
+        TlsClient {
+            config,
+            server_name: Some(server_name),
+        }
+    }
+
+    pub fn pinned(certificate: CertificateDer<'static>) -> Self {
+        let verifier = PinnedCertificate {
+            certificate,
+            algorithms: crypto::ring::default_provider().signature_verification_algorithms,
+        };
+        let config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        TlsClient {
+            config: Arc::new(config),
+            server_name: None,
+        }
+    }
+
+    pub fn pinned_pem(certificate: &[u8]) -> Result<Self, io::Error> {
+        Ok(Self::pinned(
+            CertificateDer::from_pem_slice(certificate).map_err(pem_error)?,
+        ))
+    }
+
+    pub(super) fn connect(
+        &self,
+        stream: &TcpStream,
+        socket: SocketAddr,
+    ) -> Result<TlsSession, io::Error> {
+        let server_name =
+            (self.server_name.clone()).unwrap_or_else(|| ServerName::IpAddress(socket.ip().into()));
+        let connection =
+            ClientConnection::new(self.config.clone(), server_name).map_err(tls_error)?;
+        TlsSession::establish(connection.into(), stream)
+    }
+}
+
+#[derive(Debug)]
+struct PinnedCertificate {
+    certificate: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.certificate.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[derive(Debug)]
+struct TlsState {
+    connection: Connection,
+    received: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct TlsSession {
+    state: Arc<Mutex<TlsState>>,
+    // keeps tls records in order on the socket without holding the state lock while blocked
+    sending: Arc<Mutex<()>>,
+    // only the connection's reading thread locks this, so it never contends with writers
+    read_buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl TlsSession {
+    fn establish(mut connection: Connection, mut stream: &TcpStream) -> Result<Self, io::Error> {
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        Ok(TlsSession {
+            state: Arc::new(Mutex::new(TlsState {
+                connection,
+                received: Vec::new(),
+            })),
+            sending: Default::default(),
+            read_buffer: Arc::new(Mutex::new(vec![0; TLS_READ_BUFFER_SIZE])),
+        })
+    }
+
+    fn pending(connection: &mut Connection) -> Result<Vec<u8>, io::Error> {
+        let mut pending = Vec::new();
+        while connection.wants_write() {
+            connection.write_tls(&mut pending)?;
+        }
+        Ok(pending)
+    }
+
+    pub(super) fn write_all(
+        &self,
+        mut stream: &TcpStream,
+        mut buf: &[u8],
+    ) -> Result<(), io::Error> {
+        let _sending = self.sending.lock().unwrap();
+        loop {
+            // rustls refuses plaintext once its outgoing buffer is full, so drain it between chunks
+            let pending = {
+                let connection = &mut self.state.lock().unwrap().connection;
+                let len = connection.writer().write(buf)?;
+                buf = &buf[len..];
+                Self::pending(connection)?
+            };
+            if pending.is_empty() && buf.is_empty() {
+                return Ok(());
+            }
+            stream.write_all(&pending)?;
+        }
+    }
+
+    fn send_pending(&self, mut stream: &TcpStream) -> Result<(), io::Error> {
+        // a writer holding the lock sends these along with its own records
+        let Ok(_sending) = self.sending.try_lock() else {
+            return Ok(());
+        };
+        let pending = Self::pending(&mut self.state.lock().unwrap().connection)?;
+        stream.write_all(&pending)
+    }
+
+    pub(super) fn read_exact(&self, stream: &TcpStream, buf: &mut [u8]) -> Result<(), io::Error> {
+        TlsReader {
+            session: self,
+            stream,
+            raw: &mut self.read_buffer.lock().unwrap(),
+        }
+        .read_exact(buf)
+    }
+
+    pub(super) fn close(&self, mut stream: &TcpStream) -> Result<(), io::Error> {
+        let _sending = self.sending.lock().unwrap();
+        let pending = {
+            let connection = &mut self.state.lock().unwrap().connection;
+            connection.send_close_notify();
+            Self::pending(connection)?
+        };
+        stream.write_all(&pending)
+    }
+}
+
+struct TlsReader<'a> {
+    session: &'a TlsSession,
+    stream: &'a TcpStream,
+    raw: &'a mut [u8],
+}
+
+impl Read for TlsReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let TlsState {
+                    connection,
+                    received,
+                } = &mut *self.session.state.lock().unwrap();
+                match connection.reader().read(buf) {
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                if !received.is_empty() {
+                    let len = connection.read_tls(&mut received.as_slice())?;
+                    received.drain(..len);
+                    connection.process_new_packets().map_err(tls_error)?;
+                    continue;
+                }
+            }
+            self.session.send_pending(self.stream)?;
+            let len = self.stream.read(self.raw)?;
+            if len == 0 {
+                return Ok(0);
+            }
+            (self.session.state.lock().unwrap().received).extend_from_slice(&self.raw[..len]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, TcpListener, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    use super::{TlsClient, TlsServer, TlsSession};
+
+    fn exchange(session: TlsSession, stream: TcpStream, message: &[u8]) {
+        let timeout = Some(Duration::from_secs(10));
+        stream.set_read_timeout(timeout).unwrap();
+        stream.set_write_timeout(timeout).unwrap();
+        let writer = {
+            let (session, stream) = (session.clone(), stream.try_clone().unwrap());
+            let message = message.to_vec();
+            thread::spawn(move || session.write_all(&stream, &message))
+        };
+        let mut received = vec![0; message.len()];
+        session.read_exact(&stream, &mut received).unwrap();
+        writer.join().unwrap().unwrap();
+        assert!(received == message);
+    }
+
+    #[test]
+    fn test_loopback() {
+        let (server, certificate) = TlsServer::self_signed(vec!["localhost".to_string()]).unwrap();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let socket = listener.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let session = server.accept(&stream).unwrap();
+            let mut buf = vec![0; 64 * 1024];
+            session.read_exact(&stream, &mut buf).unwrap();
+            session.write_all(&stream, &buf).unwrap();
+        });
+        let stream = TcpStream::connect(socket).unwrap();
+        let session = TlsClient::pinned(certificate)
+            .connect(&stream, socket)
+            .unwrap();
+        let message: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        session.write_all(&stream, &message).unwrap();
+        let mut echo = vec![0; message.len()];
+        session.read_exact(&stream, &mut echo).unwrap();
+        assert_eq!(echo, message);
+        server_thread.join().unwrap();
+
+        let (server, _) = TlsServer::self_signed(vec!["localhost".to_string()]).unwrap();
+        let (_, wrong_certificate) = TlsServer::self_signed(vec!["localhost".to_string()]).unwrap();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let socket = listener.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            assert!(server.accept(&stream).is_err());
+        });
+        let stream = TcpStream::connect(socket).unwrap();
+        assert!(
+            TlsClient::pinned(wrong_certificate)
+                .connect(&stream, socket)
+                .is_err()
+        );
+        drop(stream);
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_simultaneous_writes() {
+        let (server, certificate) = TlsServer::self_signed(vec!["localhost".to_string()]).unwrap();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let socket = listener.local_addr().unwrap();
+        let message: Vec<u8> = (0..16 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let server_thread = {
+            let message = message.clone();
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let session = server.accept(&stream).unwrap();
+                exchange(session, stream, &message);
+            })
+        };
+        let stream = TcpStream::connect(socket).unwrap();
+        let session = TlsClient::pinned(certificate)
+            .connect(&stream, socket)
+            .unwrap();
+        exchange(session, stream, &message);
+        server_thread.join().unwrap();
+    }
+}
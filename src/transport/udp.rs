@@ -0,0 +1,557 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Receiver, Sender},
+    },
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use super::{
+    ClientEvent, ClientNetworkEvent, ClientServerMessage, Delivery, NetworkEvent, Peer,
+    ServerEvent, ServerNetworkEvent, Token,
+    codec::{Codec, CodecExt},
+    is_timeout,
+    recording::Recorder,
+    simulator::{LinkKind, NetworkConditions, SimulatedLink},
+    stats::{FrameKind, TransferStats},
+};
+
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+const UDP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const UDP_REGISTER_INTERVAL: Duration = Duration::from_millis(250);
+const UDP_REGISTER_ATTEMPTS: usize = 20;
+
+#[derive(Serialize, Deserialize)]
+enum DatagramPayload<M> {
+    Register,
+    Registered,
+    Message { sequence: Option<u64>, message: M },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Datagram<M> {
+    token: Token,
+    payload: DatagramPayload<M>,
+}
+
+fn encode_datagram<M>(
+    encoding: &dyn Codec,
+    token: Token,
+    payload: DatagramPayload<&M>,
+) -> Result<Vec<u8>, io::Error>
+where
+    M: Serialize,
+{
+    let datagram = encoding.encode(&Datagram { token, payload })?;
+    if datagram.len() > MAX_DATAGRAM_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::FileTooLarge,
+            format!("Datagram size cannot exceed {} bytes", MAX_DATAGRAM_SIZE),
+        ));
+    }
+    Ok(datagram)
+}
+
+pub(super) fn decode_datagram_message<M>(encoding: &dyn Codec, buf: &[u8]) -> Result<M, io::Error>
+where
+    M: DeserializeOwned,
+{
+    match encoding.decode::<Datagram<M>>(buf)?.payload {
+        DatagramPayload::Message { message, .. } => Ok(message),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Datagram does not contain a message",
+        )),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct UdpChannel {
+    socket: Arc<UdpSocket>,
+    server: Option<SocketAddr>,
+    peer: Arc<Mutex<Option<SocketAddr>>>,
+    token: Token,
+    sequence: Arc<AtomicU64>,
+    encoding: Arc<dyn Codec>,
+    simulator: Option<Arc<SimulatedLink>>,
+    stats: Arc<TransferStats>,
+    recorder: Option<Recorder>,
+}
+
+impl UdpChannel {
+    pub(super) fn connect(
+        server: SocketAddr,
+        token: Token,
+        encoding: Arc<dyn Codec>,
+        stats: Arc<TransferStats>,
+        recorder: Option<Recorder>,
+    ) -> Result<UdpChannel, io::Error> {
+        let local = match server {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_read_timeout(Some(UDP_POLL_INTERVAL))?;
+        Ok(UdpChannel {
+            socket: Arc::new(socket),
+            server: Some(server),
+            peer: Arc::new(Mutex::new(None)),
+            token,
+            sequence: Arc::new(AtomicU64::new(0)),
+            encoding,
+            simulator: None,
+            stats,
+            recorder,
+        })
+    }
+
+    pub(super) fn token(&self) -> Token {
+        self.token
+    }
+
+    pub(super) fn is_ready(&self) -> bool {
+        self.peer.lock().unwrap().is_some()
+    }
+
+    pub(super) fn send<M>(&self, message: &M, delivery: Delivery) -> Result<(), io::Error>
+    where
+        M: Serialize,
+    {
+        let Some(peer) = *self.peer.lock().unwrap() else {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "Peer has not registered a UDP address",
+            ));
+        };
+        let sequence = matches!(delivery, Delivery::Sequenced)
+            .then(|| self.sequence.fetch_add(1, Ordering::Relaxed));
+        let datagram = encode_datagram(
+            &*self.encoding,
+            self.token,
+            DatagramPayload::Message { sequence, message },
+        )?;
+        let len = datagram.len();
+        match &self.simulator {
+            Some(simulator) => simulator.submit(datagram)?,
+            None => {
+                self.socket.send_to(&datagram, peer)?;
+            }
+        }
+        self.stats.sent(FrameKind::Datagram, len);
+        Ok(())
+    }
+
+    pub(super) fn simulate(&mut self, conditions: NetworkConditions) {
+        let (socket, peer) = (self.socket.clone(), self.peer.clone());
+        self.simulator = Some(SimulatedLink::spawn(
+            conditions,
+            LinkKind::Datagram,
+            move |datagram| {
+                if let Some(peer) = *peer.lock().unwrap()
+                    && let Err(err) = socket.send_to(datagram, peer)
+                {
+                    debug!("simulated datagram to {peer} failed: {err}");
+                }
+                Ok(())
+            },
+        ));
+    }
+
+    pub(super) fn client_thread<M>(
+        self,
+        event_sender: Sender<impl From<ClientNetworkEvent<M>> + Send + 'static>,
+        deathswitch: Receiver<()>,
+    ) where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ServerMessage: TryFrom<M>,
+    {
+        let Some(server) = self.server else {
+            error!("udp client thread started on a serverside channel");
+            return;
+        };
+        let register =
+            encode_datagram::<()>(&*self.encoding, self.token, DatagramPayload::Register).unwrap();
+        let mut registered = false;
+        let mut register_attempts = 0;
+        let mut last_register: Option<Instant> = None;
+        let mut last_sequence = None;
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        while deathswitch.try_recv().is_err() {
+            if !registered
+                && last_register.is_none_or(|last| last.elapsed() >= UDP_REGISTER_INTERVAL)
+            {
+                if register_attempts < UDP_REGISTER_ATTEMPTS {
+                    if let Err(err) = self.socket.send_to(&register, server) {
+                        debug!("unable to register udp channel with {server}: {err}");
+                    }
+                    last_register = Some(Instant::now());
+                } else if register_attempts == UDP_REGISTER_ATTEMPTS {
+                    warn!("udp channel to {server} was never acknowledged; sending over tcp");
+                }
+                register_attempts += 1;
+            }
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(err) if is_timeout(&err) => continue,
+                Err(err) => {
+                    debug!("udp receive error: {err}");
+                    continue;
+                }
+            };
+            let Ok(Datagram { token, payload }) = self.encoding.decode::<Datagram<M>>(&buf[..len])
+            else {
+                debug!("received malformed datagram from server");
+                continue;
+            };
+            if token != self.token {
+                continue;
+            }
+            if !registered {
+                debug!("udp channel registered with {server}");
+                *self.peer.lock().unwrap() = Some(server);
+                registered = true;
+            }
+            match payload {
+                DatagramPayload::Registered => {}
+                DatagramPayload::Message { sequence, message } => {
+                    self.stats.received(FrameKind::Datagram, len);
+                    if let Some(sequence) = sequence {
+                        if last_sequence.is_some_and(|last| sequence <= last) {
+                            continue;
+                        }
+                        last_sequence = Some(sequence);
+                    }
+                    if let Some(recorder) = &self.recorder {
+                        recorder.datagram(&buf[..len]);
+                    }
+                    let Ok(message) = message.try_into() else {
+                        debug!("received a clientside datagram from the server");
+                        continue;
+                    };
+                    if event_sender
+                        .send(ClientEvent::Connection(NetworkEvent::Message(message)).into())
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                DatagramPayload::Register => {}
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct UdpPeer {
+    peer: Peer,
+    udp_addr: Arc<Mutex<Option<SocketAddr>>>,
+    last_sequence: Option<u64>,
+    stats: Arc<TransferStats>,
+    recorder: Option<Recorder>,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct UdpServer {
+    socket: Arc<UdpSocket>,
+    peers: Arc<Mutex<HashMap<Token, UdpPeer>>>,
+    encoding: Arc<dyn Codec>,
+}
+
+impl UdpServer {
+    pub(super) fn bind(
+        socket: SocketAddr,
+        encoding: Arc<dyn Codec>,
+    ) -> Result<UdpServer, io::Error> {
+        let udp_socket = UdpSocket::bind(socket)?;
+        udp_socket.set_read_timeout(Some(UDP_POLL_INTERVAL))?;
+        Ok(UdpServer {
+            socket: Arc::new(udp_socket),
+            peers: Default::default(),
+            encoding,
+        })
+    }
+
+    pub(super) fn register(
+        &self,
+        peer: Peer,
+        stats: Arc<TransferStats>,
+        recorder: Option<Recorder>,
+    ) -> Result<UdpChannel, io::Error> {
+        let token = Token::generate()?;
+        let udp_addr = Arc::new(Mutex::new(None));
+        self.peers.lock().unwrap().insert(
+            token,
+            UdpPeer {
+                peer,
+                udp_addr: udp_addr.clone(),
+                last_sequence: None,
+                stats: stats.clone(),
+                recorder,
+            },
+        );
+        Ok(UdpChannel {
+            socket: self.socket.clone(),
+            server: None,
+            peer: udp_addr,
+            token,
+            sequence: Arc::new(AtomicU64::new(0)),
+            encoding: self.encoding.clone(),
+            simulator: None,
+            stats,
+            recorder: None,
+        })
+    }
+
+    pub(super) fn unregister(&self, token: Token) {
+        self.peers.lock().unwrap().remove(&token);
+    }
+
+    pub(super) fn server_thread<M>(
+        self,
+        event_sender: Sender<impl From<ServerNetworkEvent<M>> + Send + 'static>,
+        deathswitch: Receiver<()>,
+    ) where
+        M: ClientServerMessage + DeserializeOwned,
+        M::ClientMessage: TryFrom<M>,
+    {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        while deathswitch.try_recv().is_err() {
+            let (len, src_addr) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if is_timeout(&err) => continue,
+                Err(err) => {
+                    debug!("udp receive error: {err}");
+                    continue;
+                }
+            };
+            let Ok(Datagram { token, payload }) = self.encoding.decode::<Datagram<M>>(&buf[..len])
+            else {
+                debug!("received malformed datagram from {src_addr}");
+                continue;
+            };
+            let mut peers = self.peers.lock().unwrap();
+            let Some(peer) = peers.get_mut(&token) else {
+                continue;
+            };
+            *peer.udp_addr.lock().unwrap() = Some(src_addr);
+            match payload {
+                DatagramPayload::Register => {
+                    let registered =
+                        encode_datagram::<()>(&*self.encoding, token, DatagramPayload::Registered)
+                            .unwrap();
+                    if let Err(err) = self.socket.send_to(&registered, src_addr) {
+                        error!("unable to acknowledge udp registration from {src_addr}: {err}");
+                    }
+                }
+                DatagramPayload::Message { sequence, message } => {
+                    peer.stats.received(FrameKind::Datagram, len);
+                    if let Some(sequence) = sequence {
+                        if peer.last_sequence.is_some_and(|last| sequence <= last) {
+                            continue;
+                        }
+                        peer.last_sequence = Some(sequence);
+                    }
+                    if let Some(recorder) = &peer.recorder {
+                        recorder.datagram(&buf[..len]);
+                    }
+                    let Ok(message) = message.try_into() else {
+                        debug!("received a serverside datagram from {src_addr}");
+                        continue;
+                    };
+                    let event = ServerEvent::Connection(peer.peer, NetworkEvent::Message(message));
+                    let _ = event_sender.send(event.into());
+                }
+                DatagramPayload::Registered => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::ErrorKind,
+        net::{Ipv4Addr, SocketAddr, UdpSocket},
+        sync::{Arc, mpsc::channel},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{Datagram, DatagramPayload, UdpChannel, UdpServer, encode_datagram};
+    use crate::transport::{
+        ClientEvent, ClientNetworkEvent, ConnectionId, Delivery, MessageServer, NetworkEvent, Peer,
+        ServerConfig, ServerEvent, ServerNetworkEvent, TlsServer, Token,
+        codec::{Bincode, Codec, CodecExt, MessagePack},
+        stats::TransferStats,
+        test::Message,
+    };
+
+    #[test]
+    fn test_udp_channel() {
+        let timeout = Duration::from_secs(5);
+        let encoding: Arc<dyn Codec> = Arc::new(Bincode);
+        let server =
+            UdpServer::bind(SocketAddr::from(([127, 0, 0, 1], 0)), encoding.clone()).unwrap();
+        let server_addr = server.socket.local_addr().unwrap();
+        let peer = Peer {
+            id: ConnectionId(1),
+            addr: SocketAddr::from(([127, 0, 0, 1], 1)),
+        };
+        let serverside = server
+            .register(peer, Arc::new(TransferStats::new()), None)
+            .unwrap();
+        assert!(!serverside.is_ready());
+        let clientside = UdpChannel::connect(
+            server_addr,
+            serverside.token(),
+            encoding,
+            Arc::new(TransferStats::new()),
+            None,
+        )
+        .unwrap();
+        assert!(!clientside.is_ready());
+        let unregistered = clientside.send(&Message::Client("early".into()), Delivery::Unreliable);
+        assert_eq!(unregistered.unwrap_err().kind(), ErrorKind::NotConnected);
+
+        let (server_sender, server_events) = channel::<ServerNetworkEvent<Message>>();
+        let (server_kill, server_deathswitch) = channel();
+        let server_thread = thread::spawn({
+            let server = server.clone();
+            move || server.server_thread::<Message>(server_sender, server_deathswitch)
+        });
+        let (client_sender, client_events) = channel::<ClientNetworkEvent<Message>>();
+        let (client_kill, client_deathswitch) = channel();
+        let client_thread = thread::spawn({
+            let clientside = clientside.clone();
+            move || clientside.client_thread::<Message>(client_sender, client_deathswitch)
+        });
+
+        let start = Instant::now();
+        while !serverside.is_ready() || !clientside.is_ready() {
+            assert!(start.elapsed() < timeout, "udp channel never registered");
+            thread::sleep(Duration::from_millis(10));
+        }
+        (serverside.send(&Message::Server(7), Delivery::Unreliable)).unwrap();
+        assert!(matches!(
+            client_events.recv_timeout(timeout),
+            Ok(ClientEvent::Connection(NetworkEvent::Message(7)))
+        ));
+        (clientside.send(&Message::Client("ping".into()), Delivery::Sequenced)).unwrap();
+        assert!(matches!(
+            server_events.recv_timeout(timeout),
+            Ok(ServerEvent::Connection(sender, NetworkEvent::Message(message)))
+                if sender == peer && message == "ping"
+        ));
+
+        server.unregister(serverside.token());
+        (clientside.send(&Message::Client("gone".into()), Delivery::Unreliable)).unwrap();
+        assert!(
+            server_events
+                .recv_timeout(Duration::from_millis(300))
+                .is_err()
+        );
+
+        server_kill.send(()).unwrap();
+        client_kill.send(()).unwrap();
+        server_thread.join().unwrap();
+        client_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_udp_datagrams() {
+        let timeout = Duration::from_secs(5);
+        let encoding: Arc<dyn Codec> = Arc::new(MessagePack);
+        let server =
+            UdpServer::bind(SocketAddr::from(([127, 0, 0, 1], 0)), encoding.clone()).unwrap();
+        let server_addr = server.socket.local_addr().unwrap();
+        let peer = Peer {
+            id: ConnectionId(1),
+            addr: SocketAddr::from(([127, 0, 0, 1], 1)),
+        };
+        let serverside = server
+            .register(peer, Arc::new(TransferStats::new()), None)
+            .unwrap();
+        let token = serverside.token();
+        let forged = Token::generate().unwrap();
+        let (server_sender, server_events) = channel::<ServerNetworkEvent<Message>>();
+        let (server_kill, server_deathswitch) = channel();
+        let server_thread = thread::spawn({
+            let server = server.clone();
+            move || server.server_thread::<Message>(server_sender, server_deathswitch)
+        });
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        client.set_read_timeout(Some(timeout)).unwrap();
+        let send = |token: Token, payload: DatagramPayload<&Message>| {
+            let datagram = encode_datagram(&*encoding, token, payload).unwrap();
+            client.send_to(&datagram, server_addr).unwrap();
+        };
+        send(forged, DatagramPayload::Register);
+        send(token, DatagramPayload::Register);
+        let mut buf = vec![0; 1024];
+        let len = client.recv(&mut buf).unwrap();
+        let reply = encoding.decode::<Datagram<()>>(&buf[..len]).unwrap();
+        assert_eq!(reply.token, token);
+        assert!(matches!(reply.payload, DatagramPayload::Registered));
+        assert!(serverside.is_ready());
+
+        for (sequence, message) in [(Some(5), "a"), (Some(3), "stale"), (Some(5), "repeat")] {
+            let message = &Message::Client(message.into());
+            send(token, DatagramPayload::Message { sequence, message });
+        }
+        let message = &Message::Client("forged".into());
+        send(
+            forged,
+            DatagramPayload::Message {
+                sequence: None,
+                message,
+            },
+        );
+        for (sequence, message) in [(None, "b"), (Some(6), "c")] {
+            let message = &Message::Client(message.into());
+            send(token, DatagramPayload::Message { sequence, message });
+        }
+        let received: Vec<_> = (0..3)
+            .map(|_| match server_events.recv_timeout(timeout) {
+                Ok(ServerEvent::Connection(sender, NetworkEvent::Message(message)))
+                    if sender == peer =>
+                {
+                    message
+                }
+                _ => panic!("expected a datagram from {peer}"),
+            })
+            .collect();
+        assert_eq!(received, ["a", "b", "c"]);
+        assert!(
+            server_events
+                .recv_timeout(Duration::from_millis(300))
+                .is_err()
+        );
+
+        server_kill.send(()).unwrap();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_udp_refused_with_tls() {
+        let (tls, _) = TlsServer::self_signed(vec!["localhost".to_string()]).unwrap();
+        let config = ServerConfig {
+            udp: true,
+            tls: Some(tls),
+            ..Default::default()
+        };
+        let (event_sender, _) = channel::<ServerNetworkEvent<Message>>();
+        let socket = (Ipv4Addr::LOCALHOST, 0);
+        let err = MessageServer::bind_with_config::<Message>(event_sender, socket, config)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}